@@ -1,6 +1,41 @@
 use derive_more::From;
 use slog::{Drain, Level, OwnedKVList, Record};
-use std::{env, str::FromStr};
+use std::{
+    env,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        RwLock,
+    },
+};
+
+lazy_static::lazy_static! {
+    /// Filter that was set at runtime and overrides all loggers.
+    static ref FILTER: RwLock<Option<Directives>> = RwLock::new(None);
+}
+
+/// Most verbose level of the `FILTER`, or 0 if it is not set.
+///
+/// Records are checked against it before taking the lock, so the
+/// lock is only taken while a filter is set and might enable them.
+static FILTER_LEVEL: AtomicUsize = AtomicUsize::new(0);
+
+/// Override the filter of all loggers, or restore it with `None`.
+pub fn set_filter(filter: Option<&str>) {
+    let directives: Option<Directives> = filter.map(|filter| filter.to_string().into());
+    let mut guard = FILTER.write().unwrap_or_else(|err| err.into_inner());
+    let level = directives.as_ref().map_or(0, |directives| {
+        directives
+            .0
+            .iter()
+            .map(|filter| filter.level.as_usize())
+            .max()
+            // An empty filter disables all records.
+            .unwrap_or(Level::Critical.as_usize())
+    });
+    *guard = directives;
+    FILTER_LEVEL.store(level, Ordering::Release);
+}
 
 #[derive(From, Debug)]
 struct Filter {
@@ -12,7 +47,7 @@ impl Filter {
     #[inline]
     pub fn match_module(&self, module: &str) -> Option<&Self> {
         self.module.as_ref().map_or(Some(self), |prefix| {
-            module.starts_with(prefix).then(|| self)
+            module.starts_with(prefix).then_some(self)
         })
     }
 
@@ -31,7 +66,7 @@ impl Directives {
         self.0
             .iter()
             .filter_map(|filter| filter.match_module(module))
-            .last()
+            .next_back()
             .map(|filter| filter.match_level(level))
             .unwrap_or_default()
     }
@@ -100,7 +135,16 @@ where
     type Ok = ();
 
     fn log(&self, info: &Record<'_>, val: &OwnedKVList) -> Result<(), T::Err> {
-        if !self.directives.is_enabled(info.module(), info.level()) {
+        let enabled = match FILTER_LEVEL.load(Ordering::Acquire) {
+            0 => self.directives.is_enabled(info.module(), info.level()),
+            level if info.level().as_usize() > level => false,
+            _ => {
+                let filter = FILTER.read().unwrap_or_else(|err| err.into_inner());
+                let directives = filter.as_ref().unwrap_or(&self.directives);
+                directives.is_enabled(info.module(), info.level())
+            }
+        };
+        if !enabled {
             return Ok(());
         }
 
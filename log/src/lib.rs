@@ -196,6 +196,15 @@ pub fn sync_logger<N: AsRef<str>, C: Into<Config>>(
     Ok(guard.into())
 }
 
+/// Change the log filter of the running process.
+///
+/// The filter has the same format as `Config::filter` and overrides
+/// the configured filter and `RUST_LOG` of all loggers; `None`
+/// restores them.
+pub fn set_filter(filter: Option<&str>) {
+    envlogger::set_filter(filter)
+}
+
 /// Helper to derive log level from verbosity.
 pub fn verbose(count: usize) -> String {
     match count {
@@ -274,7 +283,7 @@ impl Target for Syslog {
     /// Create a new background logger.
     fn new(name: &str) -> Result<Self, Error> {
         let name = name.to_string();
-        let _name = CString::new(&name[..name.find('(').unwrap_or_else(|| name.len())])?;
+        let _name = CString::new(&name[..name.find('(').unwrap_or(name.len())])?;
         let c_str: &CStr = _name.as_c_str();
 
         unsafe {
@@ -477,4 +486,41 @@ mod tests {
             debug!("Hello, World! {}", i);
         }
     }
+
+    #[test]
+    fn test_set_filter() {
+        use slog::{o, Drain, OwnedKVList, Record};
+        use std::sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        };
+
+        struct Counter(Arc<AtomicUsize>);
+
+        impl Drain for Counter {
+            type Ok = ();
+            type Err = slog::Never;
+
+            fn log(&self, _: &Record<'_>, _: &OwnedKVList) -> Result<(), slog::Never> {
+                self.0.fetch_add(1, Ordering::SeqCst);
+                Ok(())
+            }
+        }
+
+        let count = Arc::new(AtomicUsize::new(0));
+        let drain = crate::envlogger::Logger::with_default_filter(Counter(count.clone()), "info");
+        let logger = slog::Logger::root(drain.fuse(), o!());
+
+        crate::set_filter(Some("warning"));
+        slog::info!(logger, "filtered");
+        slog::warn!(logger, "logged");
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+
+        crate::set_filter(Some("privsep_log=debug"));
+        slog::debug!(logger, "logged");
+        slog::trace!(logger, "filtered");
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+
+        crate::set_filter(None);
+    }
 }
@@ -7,8 +7,8 @@ use proc_macro2::{Ident, Span, TokenStream};
 use quote::{quote, ToTokens};
 use std::collections::{HashMap, HashSet};
 use syn::{
-    parse::Parse, parse_macro_input, Attribute, Error, ItemEnum, Lit, LitStr, Meta, MetaList,
-    MetaNameValue, NestedMeta, Path,
+    parse::Parse, parse_macro_input, Attribute, Error, Fields, ItemEnum, Lit, LitStr, Meta,
+    MetaList, MetaNameValue, NestedMeta, Path,
 };
 
 /// Derive privsep processes from an enum.
@@ -18,7 +18,12 @@ use syn::{
 /// - `main_path`: Set the path of the parent or process `main` function.
 /// - `username`: Set the default or the per-process privdrop user.
 /// - `disable_privdrop`: disable privdrop for the program or process.
-#[proc_macro_derive(Privsep, attributes(connect, main_path, username, disable_privdrop))]
+/// - `transport`: Set the socket type of the channels, `"stream"` or
+///   `"seqpacket"`; the latter is not supported on macOS and iOS.
+#[proc_macro_derive(
+    Privsep,
+    attributes(connect, main_path, username, disable_privdrop, transport)
+)]
 pub fn derive_privsep(item: proc_macro::TokenStream) -> proc_macro::TokenStream {
     let input = parse_macro_input!(item as ItemEnum);
 
@@ -27,6 +32,42 @@ pub fn derive_privsep(item: proc_macro::TokenStream) -> proc_macro::TokenStream
         .into()
 }
 
+/// Derive a typed `imsg` protocol from an enum.
+///
+/// Each variant is mapped to a message ID and its fields to the
+/// payload: unit variants have an empty payload, variants with a
+/// single field use the field, and variants with multiple fields
+/// use a tuple of all fields.  Generic enums are not supported.
+///
+/// Attributes:
+/// - `id`: Set the message ID of the variant, the following variants
+///   continue with the next ID.  IDs start at `Message::RESERVED`.
+#[proc_macro_derive(Imsg, attributes(id))]
+pub fn derive_imsg(item: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = parse_macro_input!(item as ItemEnum);
+
+    derive_imsg_enum(input)
+        .unwrap_or_else(|err| err.to_compile_error())
+        .into()
+}
+
+fn parse_attribute_int(attrs: &[Attribute], name: &str) -> Result<Option<u32>, Error> {
+    if let Some(attr) = attrs.iter().find(|attr| attr.path.is_ident(name)) {
+        match attr.parse_meta()? {
+            Meta::NameValue(MetaNameValue {
+                lit: Lit::Int(lit_int),
+                ..
+            }) => Ok(Some(lit_int.base10_parse()?)),
+            meta => Err(Error::new_spanned(
+                meta,
+                format!("invalid `{}` attribute", name),
+            )),
+        }
+    } else {
+        Ok(None)
+    }
+}
+
 fn parse_attribute_value(attrs: &[Attribute], name: &str) -> Result<Option<LitStr>, Error> {
     if let Some(attr) = attrs.iter().find(|attr| attr.path.is_ident(name)) {
         match attr.parse_meta()? {
@@ -36,7 +77,7 @@ fn parse_attribute_value(attrs: &[Attribute], name: &str) -> Result<Option<LitSt
             }) => Ok(Some(lit_str)),
             meta => Err(Error::new_spanned(
                 meta,
-                &format!("invalid `{}` attribute", name),
+                format!("invalid `{}` attribute", name),
             )),
         }
     } else {
@@ -62,7 +103,7 @@ fn parse_attribute_ident(attrs: &[Attribute], name: &str) -> Result<Vec<Ident>,
             ref meta => {
                 return Err(Error::new_spanned(
                     meta,
-                    &format!("invalid `{}` attribute", name),
+                    format!("invalid `{}` attribute", name),
                 ))
             }
         }
@@ -110,6 +151,19 @@ fn derive_privsep_enum(item: ItemEnum) -> Result<TokenStream, Error> {
             "`Privsep` requires `username` attribute",
         ));
     };
+    let transport = match parse_attribute_value(attrs, "transport")? {
+        None => quote! { Stream },
+        Some(transport) => match transport.value().as_str() {
+            "stream" => quote! { Stream },
+            "seqpacket" => quote! { SeqPacket },
+            _ => {
+                return Err(Error::new_spanned(
+                    transport,
+                    "`transport` must be \"stream\" or \"seqpacket\"",
+                ))
+            }
+        },
+    };
     let doc = attrs
         .iter()
         .filter(|a| a.path.is_ident("doc"))
@@ -132,7 +186,7 @@ fn derive_privsep_enum(item: ItemEnum) -> Result<TokenStream, Error> {
             if !children.contains(entry) {
                 return Err(Error::new_spanned(
                     item,
-                    &format!("Connection to unknown process `{}`", entry),
+                    format!("Connection to unknown process `{}`", entry),
                 ));
             }
             if let Some(other) = connect_map.get_mut(entry) {
@@ -141,6 +195,15 @@ fn derive_privsep_enum(item: ItemEnum) -> Result<TokenStream, Error> {
         }
     }
 
+    // The build ID is checked by the handshake; it can be set at
+    // compile time for reproducible builds.
+    let build_id = quote! {
+        match option_env!("PRIVSEP_BUILD_ID") {
+            Some(build_id) => build_id.into(),
+            None => privsep::process::executable_id()?,
+        }
+    };
+
     let mut main_path = quote! {
         unimplemented!()
     };
@@ -176,6 +239,9 @@ fn derive_privsep_enum(item: ItemEnum) -> Result<TokenStream, Error> {
                 config: config.clone(),
                 disable_privdrop: #child_disable_privdrop,
                 username: #child_username.into(),
+                build_id: #build_id,
+                transport: privsep::net::Transport::#transport,
+                ..Default::default()
             }
         };
         child_names.push(name.clone());
@@ -316,3 +382,161 @@ fn derive_privsep_enum(item: ItemEnum) -> Result<TokenStream, Error> {
         }
     })
 }
+
+fn derive_imsg_enum(item: ItemEnum) -> Result<TokenStream, Error> {
+    let ident = &item.ident;
+    if !item.generics.params.is_empty() {
+        return Err(Error::new_spanned(
+            &item.generics,
+            "Generic enums are not supported",
+        ));
+    }
+    let mut const_id = vec![];
+    let mut ids = vec![];
+    let mut id_match = vec![];
+    let mut serialize = vec![];
+    let mut deserialize = vec![];
+    let mut seen = HashSet::new();
+
+    // The IDs continue from `Message::RESERVED` or from the last
+    // explicit `id`.  The macro does not know the value of
+    // `Message::RESERVED`, so the generated code checks the IDs
+    // against it when it is compiled.
+    let mut base = None;
+    let mut offset = 0u32;
+
+    for variant in item.variants.iter() {
+        let variant_ident = &variant.ident;
+        if let Some(id) = parse_attribute_int(&variant.attrs, "id")? {
+            base = Some(id);
+            offset = 0;
+        }
+        let (id, key) = match base {
+            Some(base) => {
+                let id = base
+                    .checked_add(offset)
+                    .ok_or_else(|| Error::new_spanned(variant, "Message ID overflow"))?;
+                (quote! { #id }, (true, id))
+            }
+            None => (
+                quote! { ::privsep::imsg::Message::RESERVED + #offset },
+                (false, offset),
+            ),
+        };
+        if !seen.insert(key) {
+            return Err(Error::new_spanned(variant, "Duplicate message ID"));
+        }
+        offset += 1;
+
+        let doc = format!("Message ID of `{}`.", variant_ident);
+        let id_name = Ident::new(
+            &(variant_ident.to_string().to_case(Case::UpperSnake) + "_ID"),
+            Span::call_site(),
+        );
+        const_id.push(quote! {
+            #[doc = #doc]
+            pub const #id_name: u32 = #id;
+        });
+        ids.push(quote! { Self::#id_name, });
+
+        let fields = variant
+            .fields
+            .iter()
+            .enumerate()
+            .map(|(i, field)| {
+                field
+                    .ident
+                    .clone()
+                    .unwrap_or_else(|| Ident::new(&format!("f{}", i), Span::call_site()))
+            })
+            .collect::<Vec<_>>();
+        let types = variant
+            .fields
+            .iter()
+            .map(|field| &field.ty)
+            .collect::<Vec<_>>();
+        let pattern = match &variant.fields {
+            Fields::Named(_) => quote! { Self::#variant_ident { #(#fields),* } },
+            Fields::Unnamed(_) => quote! { Self::#variant_ident ( #(#fields),* ) },
+            Fields::Unit => quote! { Self::#variant_ident },
+        };
+
+        id_match.push(quote! {
+            #pattern => Self::#id_name,
+        });
+
+        if fields.len() == 1 {
+            serialize.push(quote! {
+                #pattern => Serialize::serialize(#(#fields)*, serializer),
+            });
+            deserialize.push(quote! {
+                Self::#id_name => <#(#types)* as Deserialize>::deserialize(deserializer)
+                    .map(|#(#fields)*| #pattern),
+            });
+        } else {
+            serialize.push(quote! {
+                #pattern => Serialize::serialize(&(#(#fields,)*), serializer),
+            });
+            deserialize.push(quote! {
+                Self::#id_name => <(#(#types,)*) as Deserialize>::deserialize(deserializer)
+                    .map(|(#(#fields,)*)| #pattern),
+            });
+        }
+    }
+
+    Ok(quote! {
+        impl #ident {
+            #(#const_id)*
+        }
+
+        // Reject reserved and duplicate IDs at compile time.
+        const _: () = {
+            let ids = <#ident as privsep::imsg::Imsg>::IDS;
+            let mut i = 0;
+            while i < ids.len() {
+                assert!(
+                    ids[i] >= ::privsep::imsg::Message::RESERVED,
+                    "Message ID is reserved"
+                );
+                let mut j = i + 1;
+                while j < ids.len() {
+                    assert!(ids[i] != ids[j], "Duplicate message ID");
+                    j += 1;
+                }
+                i += 1;
+            }
+        };
+
+        impl privsep::imsg::Imsg for #ident {
+            const IDS: &'static [u32] = &[#(#ids)*];
+
+            #[allow(unused_variables)]
+            fn id(&self) -> u32 {
+                match self {
+                    #(#id_match)*
+                }
+            }
+
+            fn serialize_payload<S: privsep::imsg::__serde::Serializer>(
+                &self,
+                serializer: S,
+            ) -> std::result::Result<S::Ok, S::Error> {
+                use privsep::imsg::__serde::Serialize;
+                match self {
+                    #(#serialize)*
+                }
+            }
+
+            fn deserialize_payload<'de, D: privsep::imsg::__serde::Deserializer<'de>>(
+                id: u32,
+                deserializer: D,
+            ) -> std::result::Result<Self, D::Error> {
+                use privsep::imsg::__serde::{de::Error, Deserialize};
+                match id {
+                    #(#deserialize)*
+                    _ => Err(D::Error::custom("unknown message ID")),
+                }
+            }
+        }
+    })
+}
@@ -0,0 +1,127 @@
+//! Pretty-print or replay a capture of `imsg` traffic.
+//!
+//! ```text
+//! usage: imsgdump [-o] [-r] [-x] file
+//!
+//!     -o  Replay with the OpenBSD wire format.
+//!     -r  Replay the capture against a handler pair.
+//!     -x  Print a hex dump of the payloads.
+//! ```
+//!
+//! Run it with `cargo run --example imsgdump -- file`.
+//!
+//! When replaying, the frames that were sent are sent from one end of
+//! a `Handler::pair()` and the frames that were received are sent
+//! from the other end.  The messages are printed as they arrive at
+//! the opposite end, which reproduces the traffic without the
+//! original processes.  A malformed frame is reported and the replay
+//! continues with a new handler pair.
+
+use privsep::imsg::{
+    capture::{CaptureReader, Record, Replay},
+    Options, WireFormat,
+};
+use std::{env, io, process, time::SystemTime};
+
+#[derive(Debug, Default)]
+struct Args {
+    openbsd: bool,
+    replay: bool,
+    hexdump: bool,
+    path: String,
+}
+
+fn usage() -> ! {
+    eprintln!("usage: imsgdump [-o] [-r] [-x] file");
+    process::exit(1);
+}
+
+fn parse_args() -> Args {
+    let mut args = Args::default();
+    let mut path = None;
+    for arg in env::args().skip(1) {
+        match arg.as_str() {
+            "-o" => args.openbsd = true,
+            "-r" => args.replay = true,
+            "-x" => args.hexdump = true,
+            _ if arg.starts_with('-') || path.is_some() => usage(),
+            _ => path = Some(arg),
+        }
+    }
+    args.path = path.unwrap_or_else(|| usage());
+    args
+}
+
+fn print_record(record: &Record, start: SystemTime, hexdump: bool) {
+    let time = record.time.duration_since(start).unwrap_or_default();
+    println!(
+        "{:6}.{:06} {}",
+        time.as_secs(),
+        time.subsec_micros(),
+        record
+    );
+    if hexdump {
+        for (offset, line) in record.data.chunks(16).enumerate() {
+            let hex = line
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect::<Vec<_>>()
+                .join(" ");
+            let ascii = line
+                .iter()
+                .map(|byte| match byte {
+                    0x20..=0x7e => *byte as char,
+                    _ => '.',
+                })
+                .collect::<String>();
+            println!("    {:04x}  {:<47}  {}", offset * 16, hex, ascii);
+        }
+    }
+}
+
+async fn replay(args: &Args, records: Vec<Record>) -> io::Result<()> {
+    let options = Options {
+        wire_format: if args.openbsd {
+            WireFormat::OpenBsd
+        } else {
+            WireFormat::Native
+        },
+        ..Default::default()
+    };
+    let mut replay = Replay::new(options)?;
+    let start = SystemTime::now();
+
+    for record in records {
+        match replay.replay(&record).await {
+            Ok(Some(received)) => print_record(&received, start, args.hexdump),
+            Ok(None) => (),
+            Err(err) => println!("error: {}", err),
+        }
+    }
+
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() {
+    let args = parse_args();
+
+    let records = CaptureReader::open(&args.path)
+        .and_then(|reader| reader.collect::<io::Result<Vec<_>>>())
+        .unwrap_or_else(|err| {
+            eprintln!("imsgdump: {}: {}", args.path, err);
+            process::exit(1);
+        });
+
+    if args.replay {
+        if let Err(err) = replay(&args, records).await {
+            eprintln!("imsgdump: {}", err);
+            process::exit(1);
+        }
+    } else if let Some(first) = records.first() {
+        let start = first.time;
+        for record in &records {
+            print_record(record, start, args.hexdump);
+        }
+    }
+}
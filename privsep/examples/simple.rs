@@ -22,7 +22,7 @@ mod parent {
     use nix::sys::wait::{waitpid, WaitStatus};
     use privsep::{
         net::Fd,
-        process::{daemon, Parent},
+        process::{daemon, Control, Parent},
     };
     use privsep_log::{info, warn};
     use std::{net::TcpListener, os::unix::io::IntoRawFd, sync::Arc, time::Duration};
@@ -45,6 +45,7 @@ mod parent {
         info!("Hello, parent!");
 
         let mut sigchld = signal(SignalKind::child())?;
+        let mut sighup = signal(SignalKind::hangup())?;
 
         let fd = TcpListener::bind("127.0.0.1:80")
             .ok()
@@ -57,17 +58,15 @@ mod parent {
         }
 
         // Send a message to all children.
-        for id in Privsep::PROCESS_IDS
-            .iter()
-            .filter(|id| **id != Privsep::PARENT_ID)
-        {
-            parent[*id]
-                .send_message(23u32.into(), fd.as_ref(), &())
-                .await?;
-        }
+        parent.broadcast(23u32.into(), fd.as_ref(), &()).await?;
 
         loop {
             tokio::select! {
+                _ = sighup.recv() => {
+                    // Only the hello process runs a dispatcher.
+                    info!("reloading configuration");
+                    parent.control(Some(&[Privsep::HELLO_ID]), &Control::Reload).await?;
+                }
                 _ = sigchld.recv() => {
                     match waitpid(None, None) {
                         Ok(WaitStatus::Exited(pid, status)) => {
@@ -194,7 +193,10 @@ mod child {
 /// Another unprivileged child process.
 mod hello {
     use crate::{Error, Privsep};
-    use privsep::process::Child;
+    use privsep::{
+        imsg::{Dispatcher, Event, Incoming},
+        process::Child,
+    };
     use privsep_log::{debug, info, warn};
     use std::{sync::Arc, time::Duration};
     use tokio::time::{interval, sleep};
@@ -221,46 +223,54 @@ mod hello {
             }
         });
 
-        loop {
-            tokio::select! {
-                message = child[Privsep::PARENT_ID].recv_message::<()>() => {
-                    match message? {
-                        Some((message, _, _)) => {
-                            info!(
-                                "received message {:?}", message;
-                                "source" => Privsep::Parent.as_ref(),
-                            );
-                            sleep(Duration::from_secs(1)).await;
-                            if let Err(err) = child[Privsep::PARENT_ID]
-                                .send_message(message, None, &())
-                                .await
-                            {
-                                warn!("failed to send message: {}", err);
-                            }
+        let mut dispatcher = Dispatcher::new();
+        dispatcher
+            .register(Privsep::PARENT_ID, 23, {
+                let child = child.clone();
+                move |incoming| echo(child.clone(), incoming)
+            })
+            .register(Privsep::CHILD_ID, 42, {
+                let child = child.clone();
+                move |incoming| echo(child.clone(), incoming)
+            })
+            .on_event({
+                let child = child.clone();
+                move |event| {
+                    let result = match event {
+                        Event::Disconnected(id) => Err(Error::Terminated(child[id].name)),
+                        Event::Control(control) => {
+                            info!("received control request {:?}", control);
+                            Ok(())
                         }
-                        None => break Err(Error::Terminated(Privsep::Parent.as_static_str())),
-                    }
-                }
-                message = child[Privsep::CHILD_ID].recv_message::<()>() => {
-                    match message? {
-                        Some((message, _, _)) => {
-                            info!(
-                                "received message {:?}", message;
-                                "source" => Privsep::Child.as_ref(),
-                            );
-                            sleep(Duration::from_secs(1)).await;
-                            if let Err(err) = child[Privsep::CHILD_ID]
-                                .send_message(message, None, &())
-                                .await
-                            {
-                                warn!("failed to send message: {}", err);
-                            }
+                        event => {
+                            warn!("unhandled event: {:?}", event);
+                            Ok(())
                         }
-                        None => break Err(Error::Terminated(Privsep::Child.as_static_str())),
-                    }
+                    };
+                    async { result }
                 }
-            }
+            });
+
+        dispatcher.run(&child).await
+    }
+
+    // send the received message back to the peer
+    async fn echo<const N: usize>(
+        child: Arc<Child<N>>,
+        incoming: Incoming<()>,
+    ) -> Result<(), Error> {
+        info!(
+            "received message {:?}", incoming.message;
+            "source" => child[incoming.peer].name,
+        );
+        sleep(Duration::from_secs(1)).await;
+        if let Err(err) = child[incoming.peer]
+            .send_message(incoming.message, None, &())
+            .await
+        {
+            warn!("failed to send message: {}", err);
         }
+        Ok(())
     }
 }
 
@@ -269,7 +279,6 @@ async fn main() {
     let config = Config {
         foreground: true,
         log_level: Some("debug".to_string()),
-        ..Default::default()
     };
 
     if let Err(err) = Privsep::main(config).await {
@@ -34,6 +34,15 @@ pub enum Error {
     #[display(fmt = "Lost {}, terminated", "_0")]
     #[from(ignore)]
     Terminated(&'static str),
+    #[display(fmt = "Remote error: {}", "_0")]
+    #[from(ignore)]
+    RemoteError(String),
+    #[display(fmt = "Handshake with {} failed: {}", "_0", "_1")]
+    #[from(ignore)]
+    HandshakeFailed(&'static str, String),
+    #[display(fmt = "Failed to send message to {} peers", "_0.len()")]
+    #[from(ignore)]
+    SendFailed(Vec<(usize, io::Error)>),
 }
 
 impl std::error::Error for Error {}
@@ -1,38 +1,102 @@
 //! Internal message handling between privilege-separated processes.
 
-use crate::net::{AncillaryData, Fd, SocketAncillary, UnixStream, UnixStreamExt};
+use crate::net::{
+    AncillaryData, Fd, FdKind, SocketAncillary, Transport, TypedFd, UnixStream, UnixStreamExt,
+};
 use bytes::{BufMut, BytesMut};
-use derive_more::Into;
-use nix::unistd::{close, getpid};
+use derive_more::Display;
+use metrics::Counters;
+use nix::{
+    sys::socket,
+    unistd::{close, dup, getpid},
+};
 use parking_lot::Mutex;
-use serde::{de::DeserializeOwned, Serialize};
+use serde::{de::DeserializeOwned, Deserializer, Serialize, Serializer};
 use std::{
+    collections::VecDeque,
     convert::TryFrom,
     io::{self, Result},
     mem,
     os::unix::io::{AsRawFd, IntoRawFd, RawFd},
     slice,
-    sync::atomic::{AtomicBool, Ordering},
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc,
+    },
+    time::Duration,
 };
+use tokio::{sync::Notify, time::Instant};
 use zerocopy::{AsBytes, FromBytes};
 
+pub mod capture;
+pub mod codec;
+mod demux;
+mod dispatch;
+mod metrics;
+pub mod rpc;
+pub mod session;
+mod split;
+mod stream;
+
+pub use capture::Capture;
+pub use codec::{Bincode, Codec};
+pub use dispatch::{Dispatcher, Event, Incoming};
+pub use metrics::{Histogram, Metrics};
+pub use split::{ImsgReader, ImsgWriter};
+pub use stream::{ImsgSink, ImsgStream};
+
+#[doc(hidden)]
+pub use serde as __serde;
+
 /// `imsg` handler.
-#[derive(Debug, Into)]
+#[derive(Debug)]
 pub struct Handler {
     /// Async half of a UNIX socketpair.
     socket: UnixStream,
+    /// Socket type of the socketpair.
+    transport: Transport,
     /// Set after the stream was shut down.
     shutdown: AtomicBool,
+    /// Set after receiving stopped because of an invalid frame.
+    recv_shutdown: AtomicBool,
     /// Read buffer.
-    read_buffer: Mutex<BytesMut>,
+    read_buffer: Mutex<ReadBuffer>,
+    /// Queue of outgoing messages.
+    write_buffer: Mutex<WriteBuffer>,
+    /// Notified when messages remain queued after a write.
+    pending: Notify,
+    /// Bitmask of the reserved IDs that are accepted from the peer.
+    reserved: AtomicU32,
+    /// Message, byte, fd and error counters.
+    pub(crate) counters: Counters,
+    /// Time when the last message was received.
+    last_received: Mutex<Instant>,
+    /// Handler options.
+    options: Options,
 }
 
 impl From<UnixStream> for Handler {
     fn from(socket: UnixStream) -> Self {
         Self {
+            transport: Transport::of(&socket).unwrap_or_default(),
             socket,
             shutdown: Default::default(),
-            read_buffer: Mutex::new(BytesMut::with_capacity(Self::BUFFER_LENGTH)),
+            recv_shutdown: Default::default(),
+            read_buffer: Mutex::new(ReadBuffer {
+                data: BytesMut::with_capacity(Self::BUFFER_LENGTH),
+                fds: Default::default(),
+                fragments: None,
+                position: 0,
+                credentials: Default::default(),
+                discard: false,
+                rate: None,
+            }),
+            write_buffer: Default::default(),
+            pending: Default::default(),
+            reserved: Default::default(),
+            counters: Default::default(),
+            last_received: Mutex::new(Instant::now()),
+            options: Default::default(),
         }
     }
 }
@@ -40,18 +104,39 @@ impl From<UnixStream> for Handler {
 impl Handler {
     pub const BUFFER_LENGTH: usize = 0xffff;
 
+    /// Default maximum number of file descriptors per message.
+    pub const MAX_FDS: usize = 16;
+
+    /// Default maximum payload length of a reassembled message.
+    pub const MAX_LENGTH: usize = 4 * 1024 * 1024;
+
+    /// Default maximum number of buffered bytes of received messages.
+    pub const MAX_BUFFERED: usize = Self::MAX_LENGTH + 2 * Self::BUFFER_LENGTH;
+
+    /// Maximum number of queued messages that are written at once.
+    pub const MAX_IOVS: usize = 64;
+
     /// Create new handler pair.
     pub fn pair() -> Result<(Self, Self)> {
-        UnixStream::pair().map(|(a, b)| (a.into(), b.into()))
+        Self::pair_with(Transport::Stream)
+    }
+
+    /// Create new handler pair with the selected transport.
+    pub fn pair_with(transport: Transport) -> Result<(Self, Self)> {
+        transport.pair().map(|(a, b)| (a.into(), b.into()))
     }
 
     pub fn socketpair() -> Result<(Fd, Fd)> {
-        let (a, b) = Self::pair()?;
-        let fd_a = Fd::from(a.as_raw_fd());
-        let fd_b = Fd::from(b.as_raw_fd());
-        mem::forget(a);
-        mem::forget(b);
-        Ok((fd_a, fd_b))
+        Self::socketpair_with(Transport::Stream)
+    }
+
+    /// Create a socketpair with the selected transport.
+    ///
+    /// The sockets are not registered with the async runtime, so
+    /// either end can be passed to `from_raw_fd` in this process.
+    pub fn socketpair_with(transport: Transport) -> Result<(Fd, Fd)> {
+        let (a, b) = transport.std_pair()?;
+        Ok((Fd::from(a.into_raw_fd()), Fd::from(b.into_raw_fd())))
     }
 
     /// Create half of a handler pair from a file descriptor.
@@ -60,167 +145,1373 @@ impl Handler {
         unsafe { UnixStream::from_raw_fd(fd).map(Into::into) }
     }
 
+    /// Set the handler options.
+    ///
+    /// Enabling `Options::credentials` sets `SO_PASSCRED` on the
+    /// socket; if this fails, the credentials of received messages
+    /// are `None`.
+    pub fn with_options(mut self, options: Options) -> Self {
+        #[cfg(any(target_os = "android", target_os = "linux"))]
+        if options.credentials != self.options.credentials {
+            let _ = socket::setsockopt(
+                self.as_raw_fd(),
+                socket::sockopt::PassCred,
+                &options.credentials,
+            );
+        }
+        self.options = options;
+        self
+    }
+
+    /// Return the handler options.
+    pub fn options(&self) -> &Options {
+        &self.options
+    }
+
+    /// Return a snapshot of the handler metrics.
+    pub fn metrics(&self) -> Metrics {
+        self.counters.snapshot(self.queued())
+    }
+
+    /// Send a keepalive request to the remote end.
+    ///
+    /// The remote handler answers automatically while it receives
+    /// messages if it accepts `Message::PING`, see `accept_reserved`,
+    /// which resets the `idle` time of this handler when it receives
+    /// the answer.  Neither message is returned to the callers of the
+    /// receive methods.  The request is queued without waiting, so a
+    /// peer that does not read cannot block the caller.
+    pub fn ping(&self) -> Result<()> {
+        self.accept_reserved(Message::PONG, true);
+        self.send_data_nowait(Message::new(Message::PING), &[], &[])
+    }
+
+    /// Return the time since the last message was received.
+    pub fn idle(&self) -> Duration {
+        self.last_received.lock().elapsed()
+    }
+
     /// Send message to remote end.
+    ///
+    /// The message is queued completely or not at all.  If the future
+    /// is dropped while it waits for the send queue, the message is
+    /// still sent with the next write.
     pub async fn send_message<T: Serialize>(
         &self,
         message: Message,
         fd: Option<&Fd>,
         data: &T,
     ) -> Result<()> {
-        if message.id < Message::RESERVED {
-            return Err(io::Error::new(io::ErrorKind::Other, "Reserved message ID"));
-        }
-        self.send_message_internal(message, fd, data).await
+        self.send_message_with_fds(message, fd.as_slice(), data)
+            .await
+    }
+
+    /// Send message with a list of file descriptors to the remote end.
+    ///
+    /// The file descriptors are passed in a single control message
+    /// and returned in the same order by `recv_message_with_fds`.
+    pub async fn send_message_with_fds<T: Serialize>(
+        &self,
+        message: Message,
+        fds: &[&Fd],
+        data: &T,
+    ) -> Result<()> {
+        self.check_id(&message)?;
+        self.send_message_internal(message, fds, data).await
+    }
+
+    /// Send message with file descriptors that are tagged with their kind.
+    ///
+    /// The kinds are sent with the payload and verified by
+    /// `recv_message_with_typed_fds`.
+    pub async fn send_message_with_typed_fds<T: Serialize>(
+        &self,
+        message: Message,
+        fds: &[&Fd],
+        data: &T,
+    ) -> Result<()> {
+        let kinds = fds
+            .iter()
+            .map(|fd| FdKind::of(*fd))
+            .collect::<Result<Vec<_>>>()?;
+        self.send_message_with_fds(message, fds, &(kinds, data))
+            .await
     }
 
     /// Send message to the remote end.
     pub(crate) async fn send_message_internal<T: Serialize>(
         &self,
-        mut message: Message,
+        message: Message,
+        fds: &[&Fd],
+        data: &T,
+    ) -> Result<()> {
+        let data = self.encode(data)?;
+        self.send_data_internal(message, fds, &data).await
+    }
+
+    /// Send a fixed-layout struct without serializing it.
+    ///
+    /// The payload is the raw memory representation of the struct;
+    /// this bypasses the codec of the handler.
+    pub async fn send_raw<T: AsBytes + ?Sized>(
+        &self,
+        message: Message,
         fd: Option<&Fd>,
         data: &T,
     ) -> Result<()> {
+        self.check_id(&message)?;
+        self.send_data_internal(message, fd.as_slice(), data.as_bytes())
+            .await
+    }
+
+    /// Check that the message ID is not reserved.
+    ///
+    /// OpenBSD's imsg has no reserved IDs, all IDs are allowed in
+    /// the `WireFormat::OpenBsd` compatibility mode.
+    fn check_id(&self, message: &Message) -> Result<()> {
+        if message.id < Message::RESERVED && self.options.wire_format == WireFormat::Native {
+            return Err(io::Error::other("Reserved message ID"));
+        }
+        Ok(())
+    }
+
+    /// Send a message that the parent relays to another process.
+    ///
+    /// The relayed message is not fragmented, its payload must fit
+    /// into a single message.
+    pub(crate) async fn send_relay(
+        &self,
+        peer_id: usize,
+        mut message: Message,
+        fds: &[&Fd],
+        data: &[u8],
+    ) -> Result<()> {
+        self.check_id(&message)?;
+        if data.len() > Message::MAX_PAYLOAD_LENGTH {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "relayed message too long",
+            ));
+        }
+        message.length = (Message::HEADER_LENGTH + data.len()) as u16;
+        message.flags &= Message::FLAG_REQUEST | Message::FLAG_RESPONSE;
+        message.pid = getpid().as_raw();
+
+        let mut payload = Vec::with_capacity(Message::HEADER_LENGTH + data.len());
+        payload.extend_from_slice(message.as_bytes());
+        payload.extend_from_slice(data);
+        let relay = Message {
+            peer_id: peer_id as u32,
+            ..Message::new(Message::RELAY)
+        };
+        self.send_data_internal(relay, fds, &payload).await
+    }
+
+    /// Serialize the payload with the codec of the handler.
+    pub(crate) fn encode<T: Serialize>(&self, data: &T) -> Result<Vec<u8>> {
+        self.options.codec.encode(data)
+    }
+
+    /// Deserialize the payload with the codec of the handler.
+    pub(crate) fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T> {
+        codec::decode(&*self.options.codec, data).inspect_err(|_| self.decode_error())
+    }
+
+    /// Count a payload that could not be deserialized.
+    fn decode_error(&self) {
+        Counters::add(&self.counters.decode_errors, 1);
+    }
+
+    /// Count a received message that violated the protocol.
+    pub(crate) fn protocol_error(&self) {
+        Counters::add(&self.counters.protocol_errors, 1);
+    }
+
+    /// Send serialized message to the remote end.
+    ///
+    /// Payloads that do not fit into a single message are split into
+    /// fragments that are reassembled by the receiver.
+    pub(crate) async fn send_data_internal(
+        &self,
+        message: Message,
+        fds: &[&Fd],
+        data: &[u8],
+    ) -> Result<()> {
+        let frames = self.frames(message, fds, data)?;
+        self.enqueue(frames).await
+    }
+
+    /// Queue a serialized message without waiting for the send queue.
+    ///
+    /// This is used where the caller cannot await, e.g. in `Drop`.
+    pub(crate) fn send_data_nowait(
+        &self,
+        message: Message,
+        fds: &[&Fd],
+        data: &[u8],
+    ) -> Result<()> {
+        let frames = self.frames(message, fds, data)?;
+        self.push(frames)
+    }
+
+    /// Queue a serialized message if the send queue has room for it.
+    ///
+    /// This returns `WouldBlock` instead of waiting while the queue is
+    /// above the high-water mark, so a peer that does not read cannot
+    /// block the caller.  The queued message is written by
+    /// `flush_pending`.
+    pub(crate) fn try_send_data(&self, message: Message, fds: &[&Fd], data: &[u8]) -> Result<()> {
+        self.try_flush()?;
+        if self.queued() > self.options.high_water_mark {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        self.send_data_nowait(message, fds, data)
+    }
+
+    /// Encode the message into frames.
+    fn frames(&self, mut message: Message, fds: &[&Fd], data: &[u8]) -> Result<Vec<Frame>> {
         if self.shutdown.load(Ordering::SeqCst) {
             return Err(io::Error::new(
                 io::ErrorKind::NotConnected,
                 "Handler is closed",
             ));
         }
-        let data = bincode::serialize(data)
-            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        if fds.len() > self.options.max_fds {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "too many file descriptors",
+            ));
+        }
+        if data.len() > self.options.max_length {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "message too long",
+            ));
+        }
         message.pid = getpid().as_raw();
-        message.length = u16::try_from(data.len() + message.length as usize)
-            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
-        let message_length = message.length as usize;
-        let iovs = [
-            io::IoSlice::new(message.as_bytes()),
-            io::IoSlice::new(&data),
-        ];
-        let bufs = if data.is_empty() {
-            &iovs[..1]
-        } else {
-            &iovs[..]
-        };
 
-        let mut ancillary_buffer = [0; 128];
-        let mut ancillary = SocketAncillary::new(&mut ancillary_buffer[..]);
-        if let Some(fd) = fd {
-            if !ancillary.add_fds(&[fd.as_raw_fd()]) {
-                return Err(io::Error::new(io::ErrorKind::Other, "failed to add fd"));
+        // The fds are owned by the queue until they were sent.
+        let fds = fds
+            .iter()
+            .map(|fd| dup(fd.as_raw_fd()).map(Fd::from).map_err(io::Error::from))
+            .collect::<Result<Vec<_>>>()?;
+
+        if self.options.wire_format == WireFormat::OpenBsd {
+            if fds.len() > 1 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "too many file descriptors",
+                ));
+            }
+            if Message::HEADER_LENGTH + data.len() > Message::MAX_IMSGSIZE {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "message too long",
+                ));
+            }
+            message.length = (Message::HEADER_LENGTH + data.len()) as u16;
+            message.flags = if fds.is_empty() {
+                0
+            } else {
+                Message::FLAG_HASFD
+            };
+
+            return Ok(vec![Frame::new(&message, data, fds)]);
+        }
+
+        message.flags &= !Message::FLAG_MORE;
+
+        let mut fds = Some(fds);
+        let mut chunks = data.chunks(Message::MAX_PAYLOAD_LENGTH).peekable();
+        let mut frames = vec![];
+        let mut chunk: &[u8] = chunks.next().unwrap_or_default();
+
+        loop {
+            // The fds are only passed with the first fragment.
+            let fds = fds.take().unwrap_or_default();
+            let mut header = message;
+            header.length = (Message::HEADER_LENGTH + chunk.len()) as u16;
+            header.set_fds(fds.len())?;
+            if chunks.peek().is_some() {
+                header.flags |= Message::FLAG_MORE;
             }
+            frames.push(Frame::new(&header, chunk, fds));
+
+            match chunks.next() {
+                Some(next) => chunk = next,
+                None => break,
+            }
+        }
+
+        Ok(frames)
+    }
+
+    /// Queue the messages and write them to the socket.
+    ///
+    /// The caller has to wait until the queue is below the high-water
+    /// mark; everything that is still queued afterwards is written by
+    /// the next send or by `flush`.
+    async fn enqueue(&self, frames: Vec<Frame>) -> Result<()> {
+        let start = Instant::now();
+        self.push(frames)?;
+        while self.queued() > self.options.high_water_mark {
+            self.socket.writable().await?;
+            self.try_flush()?;
         }
+        self.counters.send_latency.record(start.elapsed());
 
-        let length = self
-            .socket
-            .send_vectored_with_ancillary(bufs, &mut ancillary)
-            .await?;
+        Ok(())
+    }
 
-        if length != message_length {
-            return Err(io::Error::new(io::ErrorKind::WriteZero, "short message"));
+    /// Queue the messages and write as much as possible.
+    fn push(&self, frames: Vec<Frame>) -> Result<()> {
+        let message = Message::read_from_prefix(&frames[0].data[..]).expect("header");
+        if !self.is_keepalive(&message) {
+            Counters::add(&self.counters.messages_sent, 1);
+        }
+        for frame in &frames {
+            Counters::add(&self.counters.bytes_sent, frame.data.len());
+            Counters::add(&self.counters.fds_sent, frame.fds.len());
+        }
+        if let Some(ref capture) = self.options.capture {
+            for frame in &frames {
+                let (header, payload) = frame.data.split_at(Message::HEADER_LENGTH);
+                let message = Message::read_from(header).expect("header");
+                if !self.is_keepalive(&message) {
+                    capture.record(capture::Direction::Sent, &message, frame.fds.len(), payload);
+                }
+            }
+        }
+        {
+            // Fragments of different messages must not be interleaved.
+            let mut write_buffer = self.write_buffer.lock();
+            for frame in frames {
+                write_buffer.length += frame.data.len();
+                write_buffer.frames.push_back(frame);
+            }
+            self.counters
+                .max_queued
+                .fetch_max(write_buffer.length as u64, Ordering::Relaxed);
+        }
+
+        self.try_flush()?;
+        if self.queued() > 0 {
+            self.pending.notify_one();
+        }
+        Ok(())
+    }
+
+    /// Wait for queued messages and write them to the socket.
+    ///
+    /// Messages that were queued without waiting, like relayed
+    /// messages or keepalive answers, would otherwise stay queued
+    /// until the next send.  This never returns while nothing is
+    /// queued.
+    pub(crate) async fn flush_pending(&self) -> Result<()> {
+        if self.queued() == 0 {
+            self.pending.notified().await;
+        }
+        self.flush().await
+    }
+
+    /// Write all queued messages to the socket.
+    pub async fn flush(&self) -> Result<()> {
+        self.try_flush()?;
+        while self.queued() > 0 {
+            self.socket.writable().await?;
+            self.try_flush()?;
+        }
+        Ok(())
+    }
+
+    /// Return the socket type of the channel.
+    ///
+    /// A handler that was created from a file descriptor detects the
+    /// transport of the socket.
+    pub fn transport(&self) -> Transport {
+        self.transport
+    }
+
+    /// Return the number of bytes in the send queue.
+    pub fn queued(&self) -> usize {
+        self.write_buffer.lock().length
+    }
+
+    /// Write as many queued messages as possible without blocking.
+    pub(crate) fn try_flush(&self) -> Result<()> {
+        let mut write_buffer = self.write_buffer.lock();
+        let mut ancillary_buffer = vec![0; self.options.ancillary_length()];
+
+        while !write_buffer.frames.is_empty() {
+            let mut ancillary = SocketAncillary::new(&mut ancillary_buffer[..]);
+            let length = {
+                let mut frames = write_buffer.frames.iter();
+                let first = frames.next().expect("frame");
+                let fds = first
+                    .fds
+                    .iter()
+                    .map(|fd| fd.as_raw_fd())
+                    .collect::<Vec<_>>();
+                if !fds.is_empty() && !ancillary.add_fds(&fds) {
+                    return Err(io::Error::other("failed to add fd"));
+                }
+
+                // Coalesce the following messages into a single
+                // write.  A message with fds always starts a new
+                // write, so its fds arrive with its first byte.  Each
+                // record of a `SeqPacket` socket is a single frame.
+                let max_iovs = match self.transport {
+                    Transport::Stream => Self::MAX_IOVS,
+                    Transport::SeqPacket => 1,
+                };
+                let iovs = Some(io::IoSlice::new(&first.data[first.offset..]))
+                    .into_iter()
+                    .chain(
+                        frames
+                            .take_while(|frame| frame.fds.is_empty())
+                            .map(|frame| io::IoSlice::new(&frame.data)),
+                    )
+                    .take(max_iovs)
+                    .collect::<Vec<_>>();
+
+                match self
+                    .socket
+                    .try_send_vectored_with_ancillary(&iovs, &mut ancillary)
+                {
+                    Ok(0) => return Err(io::Error::new(io::ErrorKind::WriteZero, "short message")),
+                    Ok(length) => length,
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(err) => return Err(err),
+                }
+            };
+
+            // Don't send the fds again with the remaining data.
+            write_buffer.frames[0].fds.clear();
+            write_buffer.consume(length);
         }
 
         Ok(())
     }
 
     /// Receive message from the remote end.
+    ///
+    /// Only the first file descriptor is returned, any additional
+    /// ones that were passed with the message are closed.
+    ///
+    /// # Cancel safety
+    ///
+    /// This method is cancel safe.  Partially received messages,
+    /// fragments, and file descriptors are kept in the read buffer of
+    /// the handler, so it can be used in a `tokio::select!` branch and
+    /// the next call continues where the dropped one stopped.
     pub async fn recv_message<T: DeserializeOwned>(
         &self,
     ) -> Result<Option<(Message, Option<Fd>, T)>> {
+        self.recv_message_with_fds().await.map(|result| {
+            result.map(|(message, fds, data)| (message, fds.into_iter().next(), data))
+        })
+    }
+
+    /// Receive message with all passed file descriptors from the remote end.
+    ///
+    /// This method is cancel safe, see `recv_message`.
+    pub async fn recv_message_with_fds<T: DeserializeOwned>(
+        &self,
+    ) -> Result<Option<(Message, Vec<Fd>, T)>> {
+        let Received {
+            message, fds, data, ..
+        } = match self.recv_data_internal().await? {
+            Some(received) => received,
+            None => return Ok(None),
+        };
+
+        let result = self.decode(&data)?;
+
+        Ok(Some((message, fds, result)))
+    }
+
+    /// Receive message with file descriptors of a verified kind.
+    ///
+    /// The kind of each received file descriptor must match the kind
+    /// that was tagged by `send_message_with_typed_fds`.  This method
+    /// is cancel safe, see `recv_message`.
+    pub async fn recv_message_with_typed_fds<T: DeserializeOwned>(
+        &self,
+    ) -> Result<Option<(Message, Vec<TypedFd>, T)>> {
+        let (message, fds, (kinds, data)) =
+            match self.recv_message_with_fds::<(Vec<FdKind>, T)>().await? {
+                Some(result) => result,
+                None => return Ok(None),
+            };
+
+        if fds.len() != kinds.len() {
+            self.protocol_error();
+            return Err(if fds.len() < kinds.len() {
+                ProtocolError::MissingFds(kinds.len())
+            } else {
+                ProtocolError::TooManyFds(fds.len())
+            }
+            .into());
+        }
+        let fds = kinds
+            .into_iter()
+            .zip(fds)
+            .map(|(kind, fd)| {
+                let fd = TypedFd::new(fd)?;
+                if fd.kind() != kind {
+                    self.protocol_error();
+                    return Err(ProtocolError::InvalidFdKind(kind, fd.kind()).into());
+                }
+                Ok(fd)
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Some((message, fds, data)))
+    }
+
+    /// Receive message with the credentials of the sending process.
+    ///
+    /// The credentials are verified by the kernel and only available
+    /// if `Options::credentials` is enabled on a supported platform.
+    /// This method is cancel safe, see `recv_message`.
+    pub async fn recv_message_with_credentials<T: DeserializeOwned>(
+        &self,
+    ) -> Result<Option<(Message, Option<Fd>, T, Option<Credentials>)>> {
+        let Received {
+            message,
+            fds,
+            credentials,
+            data,
+        } = match self.recv_data_internal().await? {
+            Some(received) => received,
+            None => return Ok(None),
+        };
+
+        let result = self.decode(&data)?;
+
+        Ok(Some((message, fds.into_iter().next(), result, credentials)))
+    }
+
+    /// Receive a fixed-layout struct without deserializing it.
+    ///
+    /// The payload length must match the size of the struct.
+    /// This method is cancel safe, see `recv_message`.
+    pub async fn recv_raw<T: FromBytes>(&self) -> Result<Option<(Message, Option<Fd>, T)>> {
+        let Received {
+            message, fds, data, ..
+        } = match self.recv_data_internal().await? {
+            Some(received) => received,
+            None => return Ok(None),
+        };
+
+        let result = T::read_from(&data[..]).ok_or_else(|| {
+            self.decode_error();
+            io::Error::new(io::ErrorKind::InvalidData, "invalid payload length")
+        })?;
+
+        Ok(Some((message, fds.into_iter().next(), result)))
+    }
+
+    /// Send a typed message to the remote end.
+    pub async fn send_imsg<M: Imsg>(&self, imsg: &M, fd: Option<&Fd>) -> Result<()> {
+        let message = Message::new(imsg.id());
+        self.check_id(&message)?;
+        let data = self.encode(&ImsgPayload(imsg))?;
+        self.send_data_internal(message, fd.as_slice(), &data).await
+    }
+
+    /// Receive a typed message from the remote end.
+    ///
+    /// Messages with an unknown ID or a payload that does not match
+    /// the type of the variant return a `ProtocolError`.  This method
+    /// is cancel safe, see `recv_message`.
+    pub async fn recv_imsg<M: Imsg>(&self) -> Result<Option<(Message, Option<Fd>, M)>> {
+        let Received {
+            message, fds, data, ..
+        } = match self.recv_data_internal().await? {
+            Some(received) => received,
+            None => return Ok(None),
+        };
+
+        if !M::IDS.contains(&message.id) {
+            self.decode_error();
+            return Err(ProtocolError::UnknownId(message.id).into());
+        }
+        let mut result = None;
+        self.options
+            .codec
+            .decode(&data, &mut |deserializer| {
+                result = Some(M::deserialize_payload(message.id, deserializer)?);
+                Ok(())
+            })
+            .map_err(|err| {
+                self.decode_error();
+                ProtocolError::InvalidPayload(message.id, err.to_string())
+            })?;
+        let result = result.ok_or(ProtocolError::InvalidPayload(
+            message.id,
+            "missing payload".to_string(),
+        ))?;
+
+        Ok(Some((message, fds.into_iter().next(), result)))
+    }
+
+    /// Receive serialized message from the remote end.
+    ///
+    /// All state is kept in the handler and the only await point is
+    /// the readiness of the socket, which makes this cancel safe.
+    pub(crate) async fn recv_data_internal(&self) -> Result<Option<Received>> {
         if self.shutdown.load(Ordering::SeqCst) {
             return Err(io::Error::new(
                 io::ErrorKind::NotConnected,
                 "Handler is closed",
             ));
         }
+        if self.recv_shutdown.load(Ordering::SeqCst) {
+            return Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "Handler stopped receiving",
+            ));
+        }
 
-        let mut fd_result = None;
+        loop {
+            match self.try_recv_internal() {
+                Ok(result) => break Ok(result),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    // Wait for more data.  This is our yield point in the loop.
+                    self.socket.readable().await?;
+                }
+                Err(err) => {
+                    if err
+                        .get_ref()
+                        .map(|err| err.is::<ProtocolError>())
+                        .unwrap_or_default()
+                    {
+                        self.protocol_error();
+                    }
+                    break Err(err);
+                }
+            }
+        }
+    }
+
+    /// Get the next complete message and reassemble fragments.
+    fn try_recv_internal(&self) -> Result<Option<Received>> {
+        let mut read_buffer = self.read_buffer.lock();
+
+        loop {
+            let frame =
+                match read_buffer.try_recv_frame(&self.socket, self.transport, &self.options) {
+                    Ok(Some(frame)) => frame,
+                    Ok(None) => return Ok(None),
+                    Err(err) if err.kind() == io::ErrorKind::InvalidData => {
+                        read_buffer.reset();
+                        self.framing_error();
+                        return Err(err);
+                    }
+                    Err(err) => return Err(err),
+                };
+            Counters::add(
+                &self.counters.bytes_received,
+                Message::HEADER_LENGTH + frame.data.len(),
+            );
+            Counters::add(&self.counters.fds_received, frame.fds.len());
+            *self.last_received.lock() = Instant::now();
+            match self.options.capture {
+                Some(ref capture) if !self.is_keepalive(&frame.message) => capture.record(
+                    capture::Direction::Received,
+                    &frame.message,
+                    frame.fds.len(),
+                    &frame.data,
+                ),
+                _ => (),
+            }
+
+            // OpenBSD's imsg does not support fragments.
+            let message = frame.message;
+            let more = self.options.wire_format == WireFormat::Native
+                && message.flags & Message::FLAG_MORE != 0;
+
+            // Skip the remaining fragments of a dropped message.
+            if read_buffer.discard {
+                read_buffer.discard = more;
+                continue;
+            }
+
+            let mut result = match read_buffer.fragments.take() {
+                None => frame,
+                Some(first)
+                    if first.message.id != message.id
+                        || !frame.fds.is_empty()
+                        || first.credentials != frame.credentials =>
+                {
+                    // The frames cannot be assigned to a message anymore.
+                    read_buffer.reset();
+                    self.framing_error();
+                    return Err(ProtocolError::InvalidFragment(message.id).into());
+                }
+                Some(mut first) => {
+                    first.data.unsplit(frame.data);
+                    first
+                }
+            };
+
+            let limit = if result.data.len() > self.options.max_length {
+                Some(Limit::Length)
+            } else if read_buffer.data.len() + result.data.len() > self.options.max_buffered {
+                Some(Limit::Buffered)
+            } else if !more && !read_buffer.check_rate(self.options.max_rate) {
+                Some(Limit::Rate)
+            } else {
+                None
+            };
+            if let Some(limit) = limit {
+                read_buffer.discard = more;
+                Counters::add(&self.counters.dropped, 1);
+                self.limit_exceeded(limit)?;
+                continue;
+            }
+
+            if !more {
+                result.message.flags &= !Message::FLAG_MORE;
+                self.check_reserved(&result.message)?;
+                if self.is_keepalive(&result.message) {
+                    if result.message.id == Message::PING {
+                        self.answer_ping()?;
+                    }
+                    continue;
+                }
+                Counters::add(&self.counters.messages_received, 1);
+                break Ok(Some(result));
+            }
+            read_buffer.fragments = Some(result);
+        }
+    }
+
+    /// Answer a keepalive request of the peer.
+    ///
+    /// The answers that are queued because the peer does not read are
+    /// limited by `Options::max_buffered`.
+    fn answer_ping(&self) -> Result<()> {
+        if self.queued() + Message::HEADER_LENGTH > self.options.max_buffered {
+            Counters::add(&self.counters.dropped, 1);
+            return self.limit_exceeded(Limit::Buffered);
+        }
+        let _ = self.send_data_nowait(Message::new(Message::PONG), &[], &[]);
+        Ok(())
+    }
+
+    /// Return whether the message is a keepalive that the handler answers.
+    ///
+    /// Keepalives are not returned to the caller, not captured, and
+    /// not counted as messages.
+    fn is_keepalive(&self, message: &Message) -> bool {
+        self.options.wire_format == WireFormat::Native
+            && matches!(message.id, Message::PING | Message::PONG)
+    }
+
+    /// Reject reserved messages that are not accepted from the peer.
+    ///
+    /// The message and its fds are dropped, so a peer cannot inject
+    /// control messages, like a fake `CONNECT`, into the channel.
+    fn check_reserved(&self, message: &Message) -> Result<()> {
+        if message.id < Message::RESERVED
+            && self.options.wire_format == WireFormat::Native
+            && self.reserved.load(Ordering::SeqCst) & (1 << message.id) == 0
+        {
+            return Err(ProtocolError::ReservedId(message.id).into());
+        }
+        Ok(())
+    }
+
+    /// Accept or reject the reserved message ID from the peer.
+    ///
+    /// Reserved IDs are rejected by default; they are only accepted
+    /// from trusted peers, e.g. `Message::CONNECT` during startup.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the ID is not below `Message::RESERVED`.
+    pub fn accept_reserved(&self, id: u32, accept: bool) {
+        assert!(id < Message::RESERVED, "message ID {} is not reserved", id);
+        if accept {
+            self.reserved.fetch_or(1 << id, Ordering::SeqCst);
+        } else {
+            self.reserved.fetch_and(!(1 << id), Ordering::SeqCst);
+        }
+    }
+
+    /// Apply the limit policy after a limit was exceeded.
+    fn limit_exceeded(&self, limit: Limit) -> Result<()> {
+        match self.options.limit_policy {
+            LimitPolicy::Drop => Ok(()),
+            LimitPolicy::Error => Err(ProtocolError::LimitExceeded(limit).into()),
+            LimitPolicy::Disconnect => {
+                let _ = socket::shutdown(self.as_raw_fd(), socket::Shutdown::Both);
+                Err(ProtocolError::LimitExceeded(limit).into())
+            }
+        }
+    }
+
+    /// Stop receiving after an invalid frame.
+    ///
+    /// The stream cannot be resynchronized after a bad header or an
+    /// invalid fragment, so all further receive calls return
+    /// `NotConnected`; messages can still be sent to the peer.  The
+    /// `Disconnect` policy also shuts down the connection to the peer.
+    fn framing_error(&self) {
+        self.recv_shutdown.store(true, Ordering::SeqCst);
+        if self.options.limit_policy == LimitPolicy::Disconnect {
+            let _ = socket::shutdown(self.as_raw_fd(), socket::Shutdown::Both);
+        }
+    }
+
+    /// Return whether more messages can be received after the error.
+    ///
+    /// Only a dropped message can be skipped, the channel cannot be
+    /// used after an I/O error or an invalid frame.
+    pub(crate) fn is_recoverable(&self, err: &io::Error) -> bool {
+        err.kind() == io::ErrorKind::InvalidData && !self.recv_shutdown.load(Ordering::SeqCst)
+    }
+
+    /// Forcefully close the imsg handler without dropping it.
+    pub fn shutdown(&self) {
+        let fd = self.as_raw_fd();
+        let _ = close(fd);
+        self.shutdown.store(true, Ordering::SeqCst);
+    }
+}
+
+impl AsRawFd for Handler {
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket.as_raw_fd()
+    }
+}
+
+/// Typed message protocol.
+///
+/// This trait is usually implemented with `#[derive(Imsg)]` from the
+/// [`privsep-derive`] crate that maps each variant of an enum to a
+/// message ID and the variant's fields to the payload.
+///
+/// [`privsep-derive`]: https://docs.rs/privsep-derive/
+pub trait Imsg: Sized {
+    /// Message IDs of all variants.
+    const IDS: &'static [u32];
+
+    /// Return the message ID of the variant.
+    fn id(&self) -> u32;
+
+    /// Serialize the payload of the variant.
+    fn serialize_payload<S: Serializer>(
+        &self,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error>;
+
+    /// Deserialize the payload of the variant with the message ID.
+    fn deserialize_payload<'de, D: Deserializer<'de>>(
+        id: u32,
+        deserializer: D,
+    ) -> std::result::Result<Self, D::Error>;
+}
+
+/// Serializable payload of a typed message.
+struct ImsgPayload<'a, M>(&'a M);
+
+impl<M: Imsg> Serialize for ImsgPayload<'_, M> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        self.0.serialize_payload(serializer)
+    }
+}
+
+/// Errors of the `imsg` protocol.
+#[derive(Debug, Display)]
+pub enum ProtocolError {
+    #[display(fmt = "Unknown message ID {}", "_0")]
+    UnknownId(u32),
+    #[display(fmt = "Invalid payload for message ID {}: {}", "_0", "_1")]
+    InvalidPayload(u32, String),
+    #[display(fmt = "Reserved message ID {} is not accepted", "_0")]
+    ReservedId(u32),
+    #[display(fmt = "Invalid message length {}", "_0")]
+    InvalidLength(u16),
+    #[display(fmt = "Invalid message flags {:#06x}", "_0")]
+    InvalidFlags(u16),
+    #[display(fmt = "Invalid fragment of message ID {}", "_0")]
+    InvalidFragment(u32),
+    #[display(fmt = "Too many file descriptors: {}", "_0")]
+    TooManyFds(usize),
+    #[display(fmt = "Missing file descriptors: {}", "_0")]
+    MissingFds(usize),
+    #[display(fmt = "Limit exceeded: {}", "_0")]
+    LimitExceeded(Limit),
+    #[display(fmt = "Expected a {} but got a {}", "_0", "_1")]
+    InvalidFdKind(FdKind, FdKind),
+}
+
+/// Receive limit of an `imsg` handler.
+#[derive(Clone, Copy, Debug, Display, PartialEq, Eq)]
+pub enum Limit {
+    /// The payload is longer than `Options::max_length`.
+    #[display(fmt = "message length")]
+    Length,
+    /// More than `Options::max_buffered` bytes are buffered.
+    #[display(fmt = "buffered bytes")]
+    Buffered,
+    /// More than `Options::max_rate` messages per second.
+    #[display(fmt = "message rate")]
+    Rate,
+}
+
+/// What happens when a receive limit is exceeded.
+///
+/// After an invalid frame, the handler stops receiving with every
+/// policy.  It can still send messages unless the policy is
+/// `Disconnect`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LimitPolicy {
+    /// Silently drop the message.
+    Drop,
+    /// Drop the message and return `ProtocolError::LimitExceeded`.
+    Error,
+    /// Return the error and shut down the connection to the peer,
+    /// also after an invalid frame.
+    Disconnect,
+}
+
+impl std::error::Error for ProtocolError {}
+
+impl From<ProtocolError> for io::Error {
+    fn from(err: ProtocolError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    }
+}
+
+/// Buffered data and file descriptors that were received.
+#[derive(Debug)]
+struct ReadBuffer {
+    /// Received bytes.
+    data: BytesMut,
+    /// Queue of received file descriptors, in the order they were
+    /// sent, that are not yet associated with a message.
+    fds: VecDeque<Fd>,
+    /// Partially reassembled message.
+    fragments: Option<Received>,
+    /// Stream position of the first byte in `data`.
+    position: u64,
+    /// Stream positions where the credentials of the sender changed.
+    credentials: VecDeque<(u64, Credentials)>,
+    /// Set while the fragments of a dropped message are discarded.
+    discard: bool,
+    /// Start and number of messages of the current rate interval.
+    rate: Option<(Instant, u32)>,
+}
+
+impl ReadBuffer {
+    /// Drop all buffered data and file descriptors.
+    fn reset(&mut self) {
+        self.data.clear();
+        self.fds.clear();
+        self.fragments = None;
+        self.discard = false;
+    }
+
+    /// Count a received message and check if it is within the rate.
+    fn check_rate(&mut self, max_rate: Option<u32>) -> bool {
+        let max_rate = match max_rate {
+            Some(max_rate) => max_rate,
+            None => return true,
+        };
+        let now = Instant::now();
+        match self.rate {
+            Some((start, ref mut count)) if now.duration_since(start) < Duration::from_secs(1) => {
+                *count += 1;
+                *count <= max_rate
+            }
+            _ => {
+                self.rate = Some((now, 1));
+                max_rate > 0
+            }
+        }
+    }
+
+    /// Get the next complete frame from the read buffer or try to
+    /// read more data from the socket without blocking.
+    fn try_recv_frame(
+        &mut self,
+        socket: &UnixStream,
+        transport: Transport,
+        options: &Options,
+    ) -> Result<Option<Received>> {
+        let Self {
+            data: buf,
+            fds,
+            position,
+            credentials,
+            ..
+        } = self;
         let mut message = Message::default();
-        let mut message_length: usize;
 
-        let received_buf = loop {
-            let mut buf = self.read_buffer.lock();
+        loop {
+            // Number of queued fds that belong to the pending frame,
+            // the maximum if its header is incomplete.
+            let mut pending = if buf.is_empty() { 0 } else { options.max_fds };
 
             if buf.len() >= Message::HEADER_LENGTH {
                 message
                     .as_bytes_mut()
                     .copy_from_slice(&buf[..Message::HEADER_LENGTH]);
-                message_length = message.length as usize;
+                let message_length = message.length as usize;
+                let count = options.validate(&message)?;
+                pending = count;
 
-                // We have a complete message, break out of the loop.
+                // We have a complete message, return it with the
+                // file descriptors that were sent along with it.  The
+                // kernel delivers the fds with the first byte of the
+                // message, so they are already in the queue.
                 if buf.len() >= message_length {
-                    break buf.split_to(message_length);
+                    if count > fds.len() {
+                        return Err(ProtocolError::MissingFds(count).into());
+                    }
+                    let message_fds = fds.drain(..count).collect();
+                    let data = buf
+                        .split_to(message_length)
+                        .split_off(Message::HEADER_LENGTH);
+
+                    // Any remaining fds don't belong to a message
+                    // if no other message was started.
+                    if buf.is_empty() {
+                        fds.clear();
+                    }
+
+                    // Get the credentials of the first byte.
+                    while credentials.len() > 1 && credentials[1].0 <= *position {
+                        credentials.pop_front();
+                    }
+                    let credentials = credentials
+                        .front()
+                        .filter(|(start, _)| *start <= *position)
+                        .map(|(_, credentials)| *credentials);
+                    *position += message_length as u64;
+
+                    return Ok(Some(Received {
+                        message,
+                        fds: message_fds,
+                        credentials,
+                        data,
+                    }));
                 }
             }
 
-            let mut ancillary_buffer = [0u8; 128];
+            let mut ancillary_buffer = vec![0u8; options.ancillary_length()];
             let mut ancillary = SocketAncillary::new(&mut ancillary_buffer[..]);
 
-            buf.reserve(Self::BUFFER_LENGTH);
-            let slice = unsafe {
-                slice::from_raw_parts_mut(buf.chunk_mut().as_mut_ptr(), Self::BUFFER_LENGTH)
-            };
+            // One more byte than the longest frame detects records of
+            // a `SeqPacket` socket that were truncated.
+            let read_length = Handler::BUFFER_LENGTH + 1;
+            buf.reserve(read_length);
+            let slice =
+                unsafe { slice::from_raw_parts_mut(buf.chunk_mut().as_mut_ptr(), read_length) };
             let bufs = &mut [io::IoSliceMut::new(slice)][..];
 
-            // Read more data.  This is also our yield point in the loop.
-            let length = self
-                .socket
-                .recv_vectored_with_ancillary(bufs, &mut ancillary)
-                .await?;
+            // Read more data or return `WouldBlock`.
+            let length = socket.try_recv_vectored_with_ancillary(bufs, &mut ancillary)?;
             if length == 0 {
                 return Ok(None);
             }
+            let start = *position + buf.len() as u64;
             unsafe { buf.advance_mut(length) };
 
             for ancillary_result in ancillary.messages().flatten() {
-                #[allow(irrefutable_let_patterns)]
-                if let AncillaryData::ScmRights(scm_rights) = ancillary_result {
-                    for fd in scm_rights {
-                        let fd = Fd::from(fd);
-
-                        // We only return one fd per message and auto-
-                        // close all the remaining ones once the `Fd`
-                        // is dropped.
-                        if fd_result.is_none() {
-                            fd_result = Some(fd);
+                match ancillary_result {
+                    AncillaryData::ScmRights(scm_rights) => fds.extend(scm_rights.map(Fd::from)),
+                    #[cfg(any(target_os = "android", target_os = "linux"))]
+                    AncillaryData::ScmCredentials(mut scm_credentials) => {
+                        if let Some(cred) = scm_credentials.next() {
+                            let cred = Credentials {
+                                pid: cred.get_pid(),
+                                uid: cred.get_uid(),
+                                gid: cred.get_gid(),
+                            };
+                            if credentials.back().map(|(_, last)| *last) != Some(cred) {
+                                credentials.push_back((start, cred));
+                            }
                         }
                     }
                 }
             }
+
+            // A read passes at most the fds of one new message, any
+            // others were sent with partial frames and are closed.
+            if fds.len() > pending + options.max_fds {
+                let count = fds.len();
+                fds.clear();
+                return Err(ProtocolError::TooManyFds(count).into());
+            }
+
+            // Each record must be exactly one frame, the buffer was
+            // empty before the record was received.
+            if transport == Transport::SeqPacket {
+                let header = Message::read_from_prefix(&buf[..]);
+                if header.map(|header| header.length as usize) != Some(length) {
+                    let length = length.min(u16::MAX as usize) as u16;
+                    return Err(ProtocolError::InvalidLength(length).into());
+                }
+            }
+        }
+    }
+}
+
+/// Received message with its payload.
+#[derive(Debug)]
+pub(crate) struct Received {
+    /// The message header.
+    pub(crate) message: Message,
+    /// File descriptors that were passed with the message.
+    pub(crate) fds: Vec<Fd>,
+    /// Credentials of the sending process, if enabled.
+    pub(crate) credentials: Option<Credentials>,
+    /// The payload without the header.
+    pub(crate) data: BytesMut,
+}
+
+impl Received {
+    /// Return the header of the message in a `Message::RELAY` envelope.
+    ///
+    /// The relayed message must not use a reserved ID and it cannot
+    /// have any flags but `FLAG_REQUEST` or `FLAG_RESPONSE`, the fds
+    /// are passed with the envelope.
+    pub(crate) fn relayed_message(&self, options: &Options) -> Result<Message> {
+        let message = self
+            .data
+            .get(..Message::HEADER_LENGTH)
+            .and_then(Message::read_from)
+            .ok_or_else(|| {
+                ProtocolError::InvalidPayload(self.message.id, "missing relayed header".to_string())
+            })?;
+        if message.id < Message::RESERVED {
+            return Err(ProtocolError::ReservedId(message.id).into());
+        }
+        options.validate(&message)?;
+        if message.flags & !(Message::FLAG_REQUEST | Message::FLAG_RESPONSE) != 0 {
+            return Err(ProtocolError::InvalidFlags(message.flags).into());
+        }
+        Ok(message)
+    }
+
+    /// Replace the header of the message in a `Message::RELAY` envelope.
+    pub(crate) fn set_relayed_message(&mut self, message: &Message) {
+        self.data[..Message::HEADER_LENGTH].copy_from_slice(message.as_bytes());
+    }
+
+    /// Unwrap the message of a `Message::RELAY` envelope.
+    ///
+    /// The credentials of the envelope belong to the parent, not to
+    /// the original sender, so they are removed.
+    pub(crate) fn unwrap_relay(mut self, options: &Options) -> Result<Self> {
+        self.message = self.relayed_message(options)?;
+        self.data = self.data.split_off(Message::HEADER_LENGTH);
+        self.credentials = None;
+        Ok(self)
+    }
+}
+
+/// Credentials of the sending process that are verified by the kernel.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Credentials {
+    /// Process ID.
+    pub pid: libc::pid_t,
+    /// User ID.
+    pub uid: libc::uid_t,
+    /// Group ID.
+    pub gid: libc::gid_t,
+}
+
+/// Queue of outgoing messages.
+#[derive(Debug, Default)]
+struct WriteBuffer {
+    /// Queued messages.
+    frames: VecDeque<Frame>,
+    /// Number of queued bytes that were not written yet.
+    length: usize,
+}
+
+impl WriteBuffer {
+    /// Remove the bytes that were written from the queue.
+    fn consume(&mut self, mut length: usize) {
+        self.length -= length;
+        while let Some(frame) = self.frames.front_mut() {
+            let remaining = frame.data.len() - frame.offset;
+            if length < remaining {
+                frame.offset += length;
+                break;
+            }
+            length -= remaining;
+            self.frames.pop_front();
+        }
+    }
+}
+
+/// Queued message or fragment.
+#[derive(Debug)]
+struct Frame {
+    /// Encoded header and payload.
+    data: Vec<u8>,
+    /// Number of bytes that were already written.
+    offset: usize,
+    /// File descriptors that are passed with the first byte.
+    fds: Vec<Fd>,
+}
+
+impl Frame {
+    fn new(message: &Message, payload: &[u8], fds: Vec<Fd>) -> Self {
+        let mut data = Vec::with_capacity(Message::HEADER_LENGTH + payload.len());
+        data.extend_from_slice(message.as_bytes());
+        data.extend_from_slice(payload);
+        Self {
+            data,
+            offset: 0,
+            fds,
+        }
+    }
+}
+
+/// Runtime options of an `imsg` handler.
+#[derive(Clone, Debug)]
+pub struct Options {
+    /// Maximum number of file descriptors per message.
+    pub max_fds: usize,
+    /// Maximum payload length of a reassembled message.
+    pub max_length: usize,
+    /// Codec of the message payloads.
+    pub codec: Arc<dyn Codec>,
+    /// Wire format of the messages.
+    pub wire_format: WireFormat,
+    /// Number of queued bytes until senders have to wait.
+    ///
+    /// Sending a message waits until the send queue is below the
+    /// high-water mark.  The default is 0: each send waits until all
+    /// queued messages are written.  With a higher mark, messages can
+    /// stay queued until the next send or until `Handler::flush`.
+    pub high_water_mark: usize,
+    /// Receive the credentials of the sender with each message.
+    ///
+    /// This is only supported on Linux and Android.
+    pub credentials: bool,
+    /// Maximum number of buffered bytes of received messages.
+    ///
+    /// This also limits the queued answers to keepalive requests.
+    pub max_buffered: usize,
+    /// Maximum number of received messages per second.
+    ///
+    /// The keepalive `PING` and `PONG` are counted as well.
+    pub max_rate: Option<u32>,
+    /// What happens when a receive limit is exceeded.
+    pub limit_policy: LimitPolicy,
+    /// Record all sent and received frames for debugging.
+    pub capture: Option<Arc<Capture>>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            max_fds: Handler::MAX_FDS,
+            max_length: Handler::MAX_LENGTH,
+            codec: Arc::new(Bincode::default()),
+            wire_format: WireFormat::Native,
+            high_water_mark: 0,
+            credentials: false,
+            max_buffered: Handler::MAX_BUFFERED,
+            max_rate: None,
+            limit_policy: LimitPolicy::Error,
+            capture: None,
+        }
+    }
+}
+
+impl Options {
+    /// Validate the received header and return the number of fds.
+    fn validate(&self, message: &Message) -> std::result::Result<usize, ProtocolError> {
+        let max_length = match self.wire_format {
+            WireFormat::Native => u16::MAX as usize,
+            WireFormat::OpenBsd => Message::MAX_IMSGSIZE,
         };
+        if !(Message::HEADER_LENGTH..=max_length).contains(&(message.length as usize)) {
+            return Err(ProtocolError::InvalidLength(message.length));
+        }
 
-        let result = if message_length > Message::HEADER_LENGTH {
-            bincode::deserialize(&received_buf[Message::HEADER_LENGTH..message_length])
-        } else {
-            bincode::deserialize(&[])
+        if self.wire_format == WireFormat::Native {
+            let flags = message.flags & !(0xff << Message::FDS_SHIFT);
+            let valid = Message::FLAG_HASFD
+                | Message::FLAG_MORE
+                | Message::FLAG_REQUEST
+                | Message::FLAG_RESPONSE;
+            if flags & !valid != 0
+                || flags & (Message::FLAG_REQUEST | Message::FLAG_RESPONSE)
+                    == Message::FLAG_REQUEST | Message::FLAG_RESPONSE
+                || (flags & Message::FLAG_HASFD == 0 && message.flags != flags)
+            {
+                return Err(ProtocolError::InvalidFlags(message.flags));
+            }
+        }
+
+        let count = self.wire_format.fds(message);
+        if count > self.max_fds {
+            return Err(ProtocolError::TooManyFds(count));
         }
-        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
 
-        Ok(Some((message, fd_result, result)))
+        Ok(count)
     }
 
-    /// Forcefully close the imsg handler without dropping it.
-    pub fn shutdown(&self) {
-        let fd = self.as_raw_fd();
-        let _ = close(fd);
-        self.shutdown.store(true, Ordering::SeqCst);
+    /// Size of the control message buffer for the maximum number of
+    /// fds and the credentials.
+    fn ancillary_length(&self) -> usize {
+        let length = self.max_fds.max(1) * mem::size_of::<RawFd>();
+        let mut space = unsafe { libc::CMSG_SPACE(length as libc::c_uint) as usize };
+        #[cfg(any(target_os = "android", target_os = "linux"))]
+        if self.credentials {
+            let length = mem::size_of::<libc::ucred>();
+            space += unsafe { libc::CMSG_SPACE(length as libc::c_uint) as usize };
+        }
+        space
     }
 }
 
-impl AsRawFd for Handler {
-    fn as_raw_fd(&self) -> RawFd {
-        self.socket.as_raw_fd()
+/// Wire format of the messages.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WireFormat {
+    /// The native format with multiple fds and fragmentation.
+    Native,
+    /// Compatibility with OpenBSD's imsg(3) from `libutil`.
+    ///
+    /// Messages only set `IMSGF_HASFD` and pass at most one fd, they
+    /// are limited to `MAX_IMSGSIZE`, and there are no reserved IDs.
+    /// The payload is usually a C struct that is sent and received
+    /// with `Handler::send_raw` and `Handler::recv_raw`.
+    OpenBsd,
+}
+
+impl WireFormat {
+    /// Return the number of file descriptors of the message.
+    fn fds(self, message: &Message) -> usize {
+        match self {
+            Self::Native => message.fds(),
+            Self::OpenBsd => usize::from(message.flags & Message::FLAG_HASFD != 0),
+        }
     }
 }
 
 /// Internal message header.
-#[derive(Debug, AsBytes, FromBytes, Default)]
+///
+/// The layout is identical to OpenBSD's `struct imsg_hdr`.
+#[derive(Clone, Copy, Debug, AsBytes, FromBytes, Default)]
 #[repr(C)]
 pub struct Message {
     /// Request type.
     pub id: u32,
-    /// Total message length (header + payload).
+    /// Total message length (header + payload) of the first fragment.
     pub length: u16,
-    /// Optional flags.
+    /// Optional flags, the upper byte holds the number of fds.
     pub flags: u16,
     /// Optional peer ID.
     pub peer_id: u32,
@@ -232,6 +1523,56 @@ impl Message {
     /// Reserved IDs 0-10
     pub const RESERVED: u32 = 10;
 
+    /// Reserved ID to pass a connection to a peer during startup.
+    pub const CONNECT: u32 = 1;
+
+    /// Reserved ID of messages that are relayed by the parent.
+    ///
+    /// The payload is the header and payload of the relayed message,
+    /// the `peer_id` is the destination when sent to the parent and
+    /// the source when the parent forwards it.
+    pub const RELAY: u32 = 2;
+
+    /// Reserved ID to open a session, see `session::Mux`.
+    pub const SESSION_OPEN: u32 = 3;
+
+    /// Reserved ID to close a session, see `session::Mux`.
+    pub const SESSION_CLOSE: u32 = 4;
+
+    /// Reserved ID of the handshake when a channel is established.
+    pub const HANDSHAKE: u32 = 5;
+
+    /// Reserved ID of a keepalive request, see `Handler::ping`.
+    pub const PING: u32 = 6;
+
+    /// Reserved ID of the answer to a keepalive request.
+    pub const PONG: u32 = 7;
+
+    /// Reserved ID of a control request from the parent, see
+    /// `process::Control`.
+    pub const CONTROL: u32 = 8;
+
+    /// The message carries file descriptors (`IMSGF_HASFD`).
+    pub const FLAG_HASFD: u16 = 0x0001;
+
+    /// More fragments of the message follow.
+    pub const FLAG_MORE: u16 = 0x0002;
+
+    /// The message is an RPC request.
+    pub const FLAG_REQUEST: u16 = 0x0004;
+
+    /// The message is an RPC response.
+    pub const FLAG_RESPONSE: u16 = 0x0008;
+
+    /// Maximum message length of OpenBSD's imsg (`MAX_IMSGSIZE`).
+    pub const MAX_IMSGSIZE: usize = 16384;
+
+    /// Maximum payload length of a single message or fragment.
+    pub const MAX_PAYLOAD_LENGTH: usize = u16::MAX as usize - Self::HEADER_LENGTH;
+
+    /// The upper byte of the flags holds the number of fds.
+    const FDS_SHIFT: u16 = 8;
+
     /// Message header length.
     pub const HEADER_LENGTH: usize = mem::size_of::<Self>();
 
@@ -246,6 +1587,29 @@ impl Message {
         }
     }
 
+    /// Return the number of file descriptors that belong to the message.
+    ///
+    /// A message that only sets `FLAG_HASFD` carries a single fd.
+    pub fn fds(&self) -> usize {
+        if self.flags & Self::FLAG_HASFD == 0 {
+            0
+        } else {
+            usize::from(self.flags >> Self::FDS_SHIFT).max(1)
+        }
+    }
+
+    /// Set the number of file descriptors that belong to the message.
+    pub fn set_fds(&mut self, count: usize) -> Result<()> {
+        let count = u8::try_from(count).map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidInput, "too many file descriptors")
+        })?;
+        self.flags &= !(Self::FLAG_HASFD | (0xff << Self::FDS_SHIFT));
+        if count > 0 {
+            self.flags |= Self::FLAG_HASFD | (u16::from(count) << Self::FDS_SHIFT);
+        }
+        Ok(())
+    }
+
     pub fn min() -> Self {
         Self::RESERVED.into()
     }
@@ -253,7 +1617,7 @@ impl Message {
     pub fn connect(peer_id: usize) -> Self {
         Self {
             peer_id: peer_id as u32,
-            ..Self::new(1u32)
+            ..Self::new(Self::CONNECT)
         }
     }
 }
@@ -266,6 +1630,21 @@ impl<T: Into<u32>> From<T> for Message {
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flags_fds() {
+        let mut message = Message::min();
+        assert_eq!(message.fds(), 0);
+        message.set_fds(3).unwrap();
+        assert_eq!(message.fds(), 3);
+        message.set_fds(0).unwrap();
+        assert_eq!(message.flags, 0);
+        message.flags = Message::FLAG_HASFD;
+        assert_eq!(message.fds(), 1);
+        assert!(message.set_fds(256).is_err());
+    }
+
     #[test]
     fn test_empty_data() {
         let data = bincode::serialize(&()).unwrap();
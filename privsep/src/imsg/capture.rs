@@ -0,0 +1,337 @@
+//! Capture and replay of `imsg` traffic for debugging.
+//!
+//! A `Capture` that is set in `Options::capture` records every frame
+//! that the handler sends or receives, except the keepalive `PING`
+//! and `PONG` that are answered by the handler itself.  Fragments are
+//! recorded as individual frames, exactly as they are written to the
+//! socket.
+//! File descriptors cannot be captured, only their number is kept.
+//!
+//! # File format
+//!
+//! A capture file starts with the 8 byte magic `IMSGCAP1`, followed
+//! by one record per frame.  All integers are in native byte order,
+//! like the `imsg` header itself.
+//!
+//! | Offset | Length | Field                                         |
+//! |--------|--------|-----------------------------------------------|
+//! | 0      | 1      | Direction: 0 = sent, 1 = received             |
+//! | 1      | 1      | Number of passed file descriptors             |
+//! | 2      | 2      | Reserved, always 0                            |
+//! | 4      | 4      | Payload length                                |
+//! | 8      | 8      | Time in microseconds since the UNIX epoch     |
+//! | 16     | 16     | The `imsg` header as it was sent on the wire  |
+//! | 32     | n      | The payload                                   |
+//!
+//! The `imsgdump` example pretty-prints a capture file or replays it
+//! against a `Handler::pair()`.
+
+use crate::{
+    imsg::{Frame, Handler, Message, Options, WireFormat},
+    net::Fd,
+};
+use parking_lot::Mutex;
+use std::{
+    convert::{TryFrom, TryInto},
+    fmt,
+    fs::{File, OpenOptions},
+    io::{self, BufReader, Read, Result, Write},
+    os::unix::io::IntoRawFd,
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use zerocopy::{AsBytes, FromBytes};
+
+/// Magic at the start of a capture file.
+const MAGIC: &[u8; 8] = b"IMSGCAP1";
+
+/// Length of a record without the payload.
+const RECORD_LENGTH: usize = 16 + Message::HEADER_LENGTH;
+
+/// Direction of a captured frame.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    /// The frame was sent by the handler.
+    Sent,
+    /// The frame was received by the handler.
+    Received,
+}
+
+/// Writer of a capture file.
+///
+/// A capture can be shared by multiple handlers but their records are
+/// not distinguished in the file.  Errors writing the capture are
+/// ignored, so the tap never changes the behavior of the channel.
+pub struct Capture {
+    writer: Mutex<Box<dyn Write + Send>>,
+}
+
+impl fmt::Debug for Capture {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Capture").finish()
+    }
+}
+
+impl Capture {
+    /// Create a new capture file.
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        Self::new(file)
+    }
+
+    /// Write the capture to a writer.
+    ///
+    /// Each record is passed to the writer with a single `write_all`,
+    /// so an unbuffered file keeps all records up to a crash.
+    pub fn new<W: Write + Send + 'static>(mut writer: W) -> Result<Self> {
+        writer.write_all(MAGIC)?;
+        Ok(Self {
+            writer: Mutex::new(Box::new(writer)),
+        })
+    }
+
+    /// Record a frame.
+    pub(crate) fn record(&self, direction: Direction, message: &Message, fds: usize, data: &[u8]) {
+        let record = Record {
+            direction,
+            time: SystemTime::now(),
+            message: *message,
+            fds,
+            data: data.to_vec(),
+        };
+        let _ = self.writer.lock().write_all(&record.to_bytes());
+    }
+}
+
+/// Reader of a capture file.
+#[derive(Debug)]
+pub struct CaptureReader<R> {
+    reader: R,
+}
+
+impl CaptureReader<BufReader<File>> {
+    /// Open a capture file.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> CaptureReader<R> {
+    /// Read the capture from a reader.
+    pub fn new(mut reader: R) -> Result<Self> {
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid capture file",
+            ));
+        }
+        Ok(Self { reader })
+    }
+
+    fn read_record(&mut self) -> Result<Option<Record>> {
+        let mut buf = [0u8; RECORD_LENGTH];
+        match self.reader.read_exact(&mut buf[..1]) {
+            Ok(()) => (),
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err),
+        }
+        self.reader.read_exact(&mut buf[1..])?;
+
+        let direction = match buf[0] {
+            0 => Direction::Sent,
+            1 => Direction::Received,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "invalid capture direction",
+                ))
+            }
+        };
+        let fds = usize::from(buf[1]);
+        let length = u32::from_ne_bytes(buf[4..8].try_into().unwrap()) as usize;
+        let micros = u64::from_ne_bytes(buf[8..16].try_into().unwrap());
+        let message = Message::read_from(&buf[16..]).expect("header");
+        let mut data = vec![0u8; length];
+        self.reader.read_exact(&mut data)?;
+
+        Ok(Some(Record {
+            direction,
+            time: UNIX_EPOCH + Duration::from_micros(micros),
+            message,
+            fds,
+            data,
+        }))
+    }
+}
+
+impl<R: Read> Iterator for CaptureReader<R> {
+    type Item = Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_record().transpose()
+    }
+}
+
+/// Captured frame.
+#[derive(Clone, Debug)]
+pub struct Record {
+    /// Whether the frame was sent or received.
+    pub direction: Direction,
+    /// Time of the capture.
+    pub time: SystemTime,
+    /// The header as it was sent on the wire.
+    pub message: Message,
+    /// Number of passed file descriptors.
+    pub fds: usize,
+    /// The payload.
+    pub data: Vec<u8>,
+}
+
+impl Record {
+    /// Send the frame unmodified to the remote end.
+    ///
+    /// Each captured fd is replaced by a new fd of `/dev/null`.
+    pub async fn replay(&self, handler: &Handler) -> Result<()> {
+        let fds = (0..self.fds)
+            .map(|_| File::open("/dev/null").map(|file| Fd::from(file.into_raw_fd())))
+            .collect::<Result<Vec<_>>>()?;
+        handler
+            .enqueue(vec![Frame::new(&self.message, &self.data, fds)])
+            .await
+    }
+
+    /// Receive the next message from the remote end as a record.
+    ///
+    /// Fragments are reassembled and the received fds are closed.
+    pub async fn recv(handler: &Handler) -> Result<Option<Self>> {
+        Ok(handler.recv_data_internal().await?.map(|received| Self {
+            direction: Direction::Received,
+            time: SystemTime::now(),
+            message: received.message,
+            fds: received.fds.len(),
+            data: received.data.to_vec(),
+        }))
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let micros = self
+            .time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+        let mut buf = Vec::with_capacity(RECORD_LENGTH + self.data.len());
+        buf.push(match self.direction {
+            Direction::Sent => 0,
+            Direction::Received => 1,
+        });
+        buf.push(u8::try_from(self.fds).unwrap_or(u8::MAX));
+        buf.extend_from_slice(&[0, 0]);
+        buf.extend_from_slice(&(self.data.len() as u32).to_ne_bytes());
+        buf.extend_from_slice(&micros.to_ne_bytes());
+        buf.extend_from_slice(self.message.as_bytes());
+        buf.extend_from_slice(&self.data);
+        buf
+    }
+}
+
+/// Replay of captured frames against a `Handler::pair()`.
+///
+/// Sent frames are sent from one end of the pair and received frames
+/// from the other end.  All reserved message IDs are accepted, so the
+/// internal messages of a capture are replayed as well.
+#[derive(Debug)]
+pub struct Replay {
+    options: Options,
+    left: Handler,
+    right: Handler,
+}
+
+impl Replay {
+    /// Create a new handler pair with the options.
+    pub fn new(options: Options) -> Result<Self> {
+        let (left, right) = Self::pair(&options)?;
+        Ok(Self {
+            options,
+            left,
+            right,
+        })
+    }
+
+    fn pair(options: &Options) -> Result<(Handler, Handler)> {
+        let (left, right) = Handler::pair()?;
+        let pair = (
+            left.with_options(options.clone()),
+            right.with_options(options.clone()),
+        );
+        for handler in [&pair.0, &pair.1] {
+            for id in 0..Message::RESERVED {
+                handler.accept_reserved(id, true);
+            }
+        }
+        Ok(pair)
+    }
+
+    /// Replay the record and receive it at the opposite end.
+    ///
+    /// Returns `None` for all but the last fragment of a message.  An
+    /// error closes the handler pair and the next record is replayed
+    /// on a new pair, so that a malformed frame does not stop the
+    /// replay of the following records.
+    pub async fn replay(&mut self, record: &Record) -> Result<Option<Record>> {
+        let result = self.replay_internal(record).await;
+        if result.is_err() {
+            let (left, right) = Self::pair(&self.options)?;
+            self.left = left;
+            self.right = right;
+        }
+        result
+    }
+
+    async fn replay_internal(&self, record: &Record) -> Result<Option<Record>> {
+        let (sender, receiver) = match record.direction {
+            Direction::Sent => (&self.left, &self.right),
+            Direction::Received => (&self.right, &self.left),
+        };
+        record.replay(sender).await?;
+
+        // Wait for the last fragment of the message.
+        if self.options.wire_format == WireFormat::Native
+            && record.message.flags & Message::FLAG_MORE != 0
+        {
+            return Ok(None);
+        }
+        match Record::recv(receiver).await? {
+            Some(mut received) => {
+                received.direction = record.direction;
+                Ok(Some(received))
+            }
+            None => Err(io::ErrorKind::UnexpectedEof.into()),
+        }
+    }
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} id {} length {} flags {:#06x} peer_id {} pid {} fds {} payload {}",
+            match self.direction {
+                Direction::Sent => '>',
+                Direction::Received => '<',
+            },
+            self.message.id,
+            self.message.length,
+            self.message.flags,
+            self.message.peer_id,
+            self.message.pid,
+            self.fds,
+            self.data.len(),
+        )
+    }
+}
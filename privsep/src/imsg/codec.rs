@@ -0,0 +1,145 @@
+//! Payload codecs of `imsg` handlers.
+//!
+//! The codec is selected per handler with `imsg::Options`.  `Bincode`
+//! is the default, `Postcard` and `Cbor` are enabled with the
+//! `postcard` and `cbor` features.  Fixed-layout C structs can bypass
+//! the codec with `Handler::send_raw` and `Handler::recv_raw`.
+
+use bincode::Options as _;
+use serde::{
+    de::{self, DeserializeOwned, DeserializeSeed},
+    Deserializer,
+};
+use std::{
+    fmt,
+    io::{self, Result},
+};
+
+/// Callback that deserializes a payload from an erased deserializer.
+pub type Visit<'a> = dyn for<'de> FnMut(
+        &mut dyn erased_serde::Deserializer<'de>,
+    ) -> std::result::Result<(), erased_serde::Error>
+    + 'a;
+
+/// Serialization format of the message payloads.
+pub trait Codec: fmt::Debug + Send + Sync {
+    /// Serialize the payload.
+    fn encode(&self, data: &dyn erased_serde::Serialize) -> Result<Vec<u8>>;
+
+    /// Deserialize the payload by passing a deserializer to `visit`.
+    ///
+    /// Codecs must reject trailing data that was not consumed.
+    fn decode(&self, data: &[u8], visit: &mut Visit<'_>) -> Result<()>;
+}
+
+/// Deserialize a payload with the codec.
+pub fn decode<T: DeserializeOwned>(codec: &dyn Codec, data: &[u8]) -> Result<T> {
+    let mut result = None;
+    codec.decode(data, &mut |deserializer| {
+        result = Some(erased_serde::deserialize(deserializer)?);
+        Ok(())
+    })?;
+    result.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "missing payload"))
+}
+
+/// Passes the codec's deserializer to the `Visit` callback.
+struct Seed<'a, 'b>(&'a mut Visit<'b>);
+
+impl<'de> DeserializeSeed<'de> for Seed<'_, '_> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(
+        self,
+        deserializer: D,
+    ) -> std::result::Result<(), D::Error> {
+        let mut deserializer = <dyn erased_serde::Deserializer<'de>>::erase(deserializer);
+        (self.0)(&mut deserializer).map_err(de::Error::custom)
+    }
+}
+
+fn invalid_data<E: fmt::Display>(err: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err.to_string())
+}
+
+/// The `bincode` codec.
+///
+/// The encoding is compatible with `bincode::serialize`.
+#[derive(Clone, Debug, Default)]
+pub struct Bincode {
+    /// Optional maximum size of the payload.
+    pub limit: Option<u64>,
+}
+
+impl Codec for Bincode {
+    fn encode(&self, data: &dyn erased_serde::Serialize) -> Result<Vec<u8>> {
+        let options = bincode::options().with_fixint_encoding();
+        match self.limit {
+            Some(limit) => options.with_limit(limit).serialize(data),
+            None => options.serialize(data),
+        }
+        .map_err(invalid_data)
+    }
+
+    fn decode(&self, data: &[u8], visit: &mut Visit<'_>) -> Result<()> {
+        let options = bincode::options().with_fixint_encoding();
+        match self.limit {
+            Some(limit) => options
+                .with_limit(limit)
+                .deserialize_seed(Seed(visit), data),
+            None => options.deserialize_seed(Seed(visit), data),
+        }
+        .map_err(invalid_data)
+    }
+}
+
+/// The `postcard` codec.
+#[cfg(feature = "postcard")]
+#[derive(Clone, Debug, Default)]
+pub struct Postcard;
+
+#[cfg(feature = "postcard")]
+impl Codec for Postcard {
+    fn encode(&self, data: &dyn erased_serde::Serialize) -> Result<Vec<u8>> {
+        postcard::to_allocvec(data).map_err(invalid_data)
+    }
+
+    fn decode(&self, data: &[u8], visit: &mut Visit<'_>) -> Result<()> {
+        let mut deserializer = postcard::Deserializer::from_bytes(data);
+        Seed(visit)
+            .deserialize(&mut deserializer)
+            .map_err(invalid_data)?;
+        if !deserializer.finalize().map_err(invalid_data)?.is_empty() {
+            return Err(invalid_data("trailing data"));
+        }
+        Ok(())
+    }
+}
+
+/// The CBOR codec.
+#[cfg(feature = "cbor")]
+#[derive(Clone, Debug, Default)]
+pub struct Cbor;
+
+#[cfg(feature = "cbor")]
+impl Codec for Cbor {
+    fn encode(&self, data: &dyn erased_serde::Serialize) -> Result<Vec<u8>> {
+        cbor4ii::serde::to_vec(Vec::new(), &data).map_err(invalid_data)
+    }
+
+    fn decode(&self, data: &[u8], visit: &mut Visit<'_>) -> Result<()> {
+        use cbor4ii::core::{
+            dec::{Read, Reference},
+            utils::SliceReader,
+        };
+
+        let mut deserializer = cbor4ii::serde::Deserializer::new(SliceReader::new(data));
+        Seed(visit)
+            .deserialize(&mut deserializer)
+            .map_err(invalid_data)?;
+        let mut reader = deserializer.into_inner();
+        match reader.fill(1) {
+            Ok(Reference::Long([])) | Ok(Reference::Short([])) => Ok(()),
+            _ => Err(invalid_data("trailing data")),
+        }
+    }
+}
@@ -0,0 +1,74 @@
+//! Shared receive loop of the RPC and session layers.
+//!
+//! The layers own the receiving side of the channel and store the
+//! received messages in their state until a waiting task takes them.
+//! Only one task reads from the handler at a time; the other tasks
+//! wait until they are notified that a message was received.  Receive
+//! errors are stored in the state as well, so each layer can deliver
+//! them to the tasks they concern instead of the one that read them.
+
+use crate::imsg::{Handler, Received};
+use parking_lot::Mutex;
+use std::io;
+use tokio::sync::{Mutex as AsyncMutex, Notify};
+
+/// Received messages that are distributed to the waiting tasks.
+#[derive(Debug, Default)]
+pub(super) struct Demux<S> {
+    /// State with the received messages.
+    pub(super) state: Mutex<S>,
+    /// Only one task reads from the handler at a time.
+    reader: AsyncMutex<()>,
+    /// Wakes up tasks after a message was received.
+    notify: Notify,
+}
+
+impl<S> Demux<S> {
+    /// Wait until `ready` returns a value.
+    ///
+    /// One of the waiting tasks reads the next message from the
+    /// handler and stores it with `route`, `None` after the channel
+    /// was closed or the receive error, while the other ones wait to
+    /// be notified.
+    pub(super) async fn wait<T>(
+        &self,
+        handler: &Handler,
+        mut ready: impl FnMut(&mut S) -> Option<T>,
+        mut route: impl FnMut(&mut S, io::Result<Option<Received>>),
+    ) -> T {
+        loop {
+            let notified = self.notify.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
+            if let Some(result) = ready(&mut self.state.lock()) {
+                return result;
+            }
+
+            match self.reader.try_lock() {
+                Ok(guard) => {
+                    // Wake up the other tasks when the reader is
+                    // done or when this future is dropped.
+                    let _reader = Reader(guard, &self.notify);
+                    let received = handler.recv_data_internal().await;
+                    route(&mut self.state.lock(), received);
+                }
+                Err(_) => notified.await,
+            }
+        }
+    }
+}
+
+/// Copy a receive error for each of the tasks it concerns.
+pub(super) fn copy_error(err: &io::Error) -> io::Error {
+    io::Error::new(err.kind(), err.to_string())
+}
+
+/// Guard of the reading task.
+struct Reader<'a, T>(T, &'a Notify);
+
+impl<T> Drop for Reader<'_, T> {
+    fn drop(&mut self) {
+        self.1.notify_waiters();
+    }
+}
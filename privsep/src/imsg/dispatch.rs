@@ -0,0 +1,347 @@
+//! Dispatch received messages to registered handlers.
+//!
+//! The `Dispatcher` receives messages from all connected peers
+//! concurrently and calls the async handler that was registered for
+//! the message ID and peer.  Messages of the same peer are handled
+//! in order, one after another.  The handlers run on the task that
+//! awaits `Dispatcher::run`, so their futures don't have to be `Send`.
+//!
+//! Processes that are not directly connected can exchange messages
+//! through the parent with `Child::send_to`.  The dispatcher of the
+//! parent forwards them, including their fds, if the relay policy
+//! allows the route.  The dispatcher of the receiving child unwraps
+//! them and dispatches them as messages of the original sender.
+//!
+//! Control requests of the parent are reported as `Event::Control`,
+//! see `process::Control`.
+//!
+//! A peer cannot stop the dispatcher: messages that violate the
+//! protocol or cannot be decoded are dropped and reported as
+//! `Event::InvalidMessage`, and only the channel of that peer is
+//! closed if it cannot receive any more messages.
+
+use crate::{
+    error::Error,
+    imsg::{Credentials, Handler, Message, Received},
+    net::Fd,
+    process::{Control, Peer, Peers},
+};
+use futures::stream::{FuturesUnordered, StreamExt};
+use serde::de::DeserializeOwned;
+use std::{collections::HashMap, fmt, future::Future, io, pin::Pin};
+
+/// Boxed future of a registered handler.
+type BoxFuture = Pin<Box<dyn Future<Output = Result<(), Error>>>>;
+
+/// Decodes the payload and calls the registered handler.
+type Route = Box<dyn Fn(usize, &Handler, Received) -> io::Result<BoxFuture>>;
+
+/// Decides if a message may be relayed from one process to another.
+type Policy = Box<dyn Fn(usize, usize, &Message) -> bool>;
+
+/// Message that was received from a peer.
+#[derive(Debug)]
+pub struct Incoming<T> {
+    /// Index of the peer that sent the message.
+    pub peer: usize,
+    /// The message header.
+    pub message: Message,
+    /// File descriptors that were passed with the message.
+    pub fds: Vec<Fd>,
+    /// Credentials of the sending process, if enabled.
+    ///
+    /// Relayed messages have no credentials, the kernel only verifies
+    /// the parent that forwarded them.
+    pub credentials: Option<Credentials>,
+    /// The deserialized payload.
+    pub data: T,
+}
+
+/// Events that are not handled by a registered message handler.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Event {
+    /// The peer closed the channel.
+    Disconnected(usize),
+    /// No handler is registered for the message of the peer.
+    Unhandled(usize, Message),
+    /// The relay policy denied the message from the first to the
+    /// second process, the destination is not connected, or its send
+    /// queue is above the high-water mark.
+    RelayDenied(usize, usize, Message),
+    /// A message of the peer was invalid or could not be decoded.
+    ///
+    /// The message is dropped.  The peer is disconnected afterwards
+    /// if its channel cannot receive any more messages, e.g. after an
+    /// invalid frame.
+    InvalidMessage(usize, io::Error),
+    /// The parent sent a control request.
+    ///
+    /// The request was already applied if it is handled
+    /// automatically.  `Dispatcher::run` returns after the event of a
+    /// `Control::Shutdown` was handled.
+    Control(Control),
+}
+
+/// Reason why a peer is no longer served.
+enum Exit {
+    Disconnected,
+    Shutdown,
+}
+
+/// Routes received messages to async handlers.
+#[derive(Default)]
+pub struct Dispatcher {
+    /// Handlers by peer (`None` for any peer) and message ID.
+    routes: HashMap<(Option<usize>, u32), Route>,
+    /// Handler of the events.
+    events: Option<Box<dyn Fn(Event) -> BoxFuture>>,
+    /// Policy of relayed messages, if relaying is enabled.
+    relay: Option<Policy>,
+}
+
+impl fmt::Debug for Dispatcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Dispatcher")
+            .field("routes", &self.routes.keys().collect::<Vec<_>>())
+            .field("relay", &self.relay.is_some())
+            .finish()
+    }
+}
+
+impl Dispatcher {
+    /// Create a new dispatcher without handlers.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a handler for the message ID from the peer.
+    pub fn register<T, F, Fut>(&mut self, peer: usize, id: u32, handler: F) -> &mut Self
+    where
+        T: DeserializeOwned + 'static,
+        F: Fn(Incoming<T>) -> Fut + 'static,
+        Fut: Future<Output = Result<(), Error>> + 'static,
+    {
+        self.insert(Some(peer), id, handler)
+    }
+
+    /// Register a handler for the message ID from any peer.
+    ///
+    /// Handlers that are registered for a specific peer take
+    /// precedence.
+    pub fn register_any<T, F, Fut>(&mut self, id: u32, handler: F) -> &mut Self
+    where
+        T: DeserializeOwned + 'static,
+        F: Fn(Incoming<T>) -> Fut + 'static,
+        Fut: Future<Output = Result<(), Error>> + 'static,
+    {
+        self.insert(None, id, handler)
+    }
+
+    /// Register the handler of disconnects and unhandled messages.
+    ///
+    /// Without an event handler, unhandled messages are ignored.
+    pub fn on_event<F, Fut>(&mut self, handler: F) -> &mut Self
+    where
+        F: Fn(Event) -> Fut + 'static,
+        Fut: Future<Output = Result<(), Error>> + 'static,
+    {
+        self.events = Some(Box::new(move |event| Box::pin(handler(event))));
+        self
+    }
+
+    /// Relay messages between the children.
+    ///
+    /// This is used by the parent.  The policy is called with the
+    /// source and destination process IDs and the relayed message;
+    /// denied messages are dropped and reported as
+    /// `Event::RelayDenied`.
+    pub fn relay<F>(&mut self, policy: F) -> &mut Self
+    where
+        F: Fn(usize, usize, &Message) -> bool + 'static,
+    {
+        self.relay = Some(Box::new(policy));
+        self
+    }
+
+    fn insert<T, F, Fut>(&mut self, peer: Option<usize>, id: u32, handler: F) -> &mut Self
+    where
+        T: DeserializeOwned + 'static,
+        F: Fn(Incoming<T>) -> Fut + 'static,
+        Fut: Future<Output = Result<(), Error>> + 'static,
+    {
+        let route: Route = Box::new(move |peer, imsg, received| {
+            Ok(Box::pin(handler(Incoming {
+                peer,
+                message: received.message,
+                data: imsg.decode(&received.data)?,
+                fds: received.fds,
+                credentials: received.credentials,
+            })))
+        });
+        self.routes.insert((peer, id), route);
+        self
+    }
+
+    /// Receive and dispatch messages from all connected peers.
+    ///
+    /// This returns after all peers disconnected or with the first
+    /// error of a handler.  Errors of a channel only disconnect that
+    /// peer, see `Event::InvalidMessage`.
+    pub async fn run<const N: usize>(&self, peers: &Peers<N>) -> Result<(), Error> {
+        let mut channels = peers
+            .iter()
+            .enumerate()
+            .filter_map(|(id, peer)| {
+                let handler = peer.handler.as_ref()?;
+                // Only the parent relays messages to the children.
+                if id == 0 || self.relay.is_some() {
+                    handler.accept_reserved(Message::RELAY, true);
+                }
+                if id == 0 {
+                    handler.accept_reserved(Message::CONTROL, true);
+                }
+                Some(self.serve(id, handler, peers))
+            })
+            .collect::<FuturesUnordered<_>>();
+
+        while let Some(result) = channels.next().await {
+            if let Exit::Shutdown = result? {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Dispatch the messages of a single peer.
+    async fn serve(&self, id: usize, handler: &Handler, peers: &[Peer]) -> Result<Exit, Error> {
+        loop {
+            let mut peer = id;
+            let result = tokio::select! {
+                result = handler.recv_data_internal() => result,
+                result = handler.flush_pending() => match result {
+                    Ok(()) => continue,
+                    // The peer cannot receive any more messages.
+                    Err(_) => return self.disconnect(peer).await,
+                },
+            };
+            let mut received = match result {
+                Ok(Some(received)) => received,
+                Ok(None) => return self.disconnect(peer).await,
+                Err(err) => {
+                    let closed = !handler.is_recoverable(&err);
+                    self.emit(Event::InvalidMessage(peer, err)).await?;
+                    if closed {
+                        return self.disconnect(peer).await;
+                    }
+                    continue;
+                }
+            };
+
+            // Only the channel to the parent accepts control requests.
+            if received.message.id == Message::CONTROL {
+                let control = match handler.decode::<Control>(&received.data) {
+                    Ok(control) => control,
+                    Err(err) => {
+                        self.emit(Event::InvalidMessage(peer, err)).await?;
+                        continue;
+                    }
+                };
+                control.apply();
+                let shutdown = control == Control::Shutdown;
+                self.emit(Event::Control(control)).await?;
+                if shutdown {
+                    return Ok(Exit::Shutdown);
+                }
+                continue;
+            }
+
+            if received.message.id == Message::RELAY {
+                if id != 0 {
+                    self.forward(id, handler, received, peers).await?;
+                    continue;
+                }
+                // The parent sets the ID of the original sender.
+                peer = received.message.peer_id as usize;
+                received = match received.unwrap_relay(handler.options()) {
+                    Ok(received) => received,
+                    Err(err) => {
+                        handler.protocol_error();
+                        self.emit(Event::InvalidMessage(id, err)).await?;
+                        continue;
+                    }
+                };
+            }
+
+            let id = received.message.id;
+            let route = self
+                .routes
+                .get(&(Some(peer), id))
+                .or_else(|| self.routes.get(&(None, id)));
+            match route {
+                Some(route) => match route(peer, handler, received) {
+                    Ok(future) => future.await?,
+                    Err(err) => self.emit(Event::InvalidMessage(peer, err)).await?,
+                },
+                None => self.emit(Event::Unhandled(peer, received.message)).await?,
+            }
+        }
+    }
+
+    /// Forward a relayed message if the policy allows it.
+    ///
+    /// The PID of the relayed message is replaced by the PID of the
+    /// sender as seen by the parent.  Invalid or undeliverable
+    /// messages are dropped and reported as an event, the sender is
+    /// never blocked by the destination.
+    async fn forward(
+        &self,
+        from: usize,
+        handler: &Handler,
+        mut received: Received,
+        peers: &[Peer],
+    ) -> Result<(), Error> {
+        let mut message = match received.relayed_message(handler.options()) {
+            Ok(message) => message,
+            Err(err) => {
+                handler.protocol_error();
+                return self.emit(Event::InvalidMessage(from, err)).await;
+            }
+        };
+        message.pid = peers[from].pid.as_raw();
+        received.set_relayed_message(&message);
+        let to = received.message.peer_id as usize;
+        let target = peers.get(to).and_then(|peer| peer.handler.as_ref());
+
+        match (target, &self.relay) {
+            (Some(target), Some(policy)) if to != from && policy(from, to, &message) => {
+                let relay = Message {
+                    peer_id: from as u32,
+                    ..Message::new(Message::RELAY)
+                };
+                // Don't wait for a destination that does not read, the
+                // dispatcher writes the queue when it becomes writable.
+                let fds = received.fds.iter().collect::<Vec<_>>();
+                match target.try_send_data(relay, &fds, &received.data) {
+                    Ok(()) => Ok(()),
+                    Err(_) => self.emit(Event::RelayDenied(from, to, message)).await,
+                }
+            }
+            _ => self.emit(Event::RelayDenied(from, to, message)).await,
+        }
+    }
+
+    /// Report that the peer is no longer served.
+    async fn disconnect(&self, peer: usize) -> Result<Exit, Error> {
+        self.emit(Event::Disconnected(peer)).await?;
+        Ok(Exit::Disconnected)
+    }
+
+    async fn emit(&self, event: Event) -> Result<(), Error> {
+        match self.events {
+            Some(ref handler) => handler(event).await,
+            None => Ok(()),
+        }
+    }
+}
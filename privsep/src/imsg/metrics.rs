@@ -0,0 +1,211 @@
+//! Counters and latency histograms of an `imsg` handler.
+//!
+//! The handler updates its counters with relaxed atomic operations.
+//! `Handler::metrics` returns a consistent-enough `Metrics` snapshot
+//! that can be added to the snapshots of other handlers, e.g. to
+//! aggregate the metrics of all peers in the parent.
+
+use std::{
+    convert::TryFrom,
+    ops::AddAssign,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// Snapshot of the metrics of one or more handlers.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Metrics {
+    /// Number of sent messages.
+    ///
+    /// The keepalive `PING` and `PONG` are not counted as messages in
+    /// either direction, but their bytes are.
+    pub messages_sent: u64,
+    /// Number of received messages, without keepalives.
+    pub messages_received: u64,
+    /// Number of sent bytes, including headers and fragments.
+    pub bytes_sent: u64,
+    /// Number of received bytes, including headers and fragments.
+    pub bytes_received: u64,
+    /// Number of passed file descriptors.
+    pub fds_sent: u64,
+    /// Number of received file descriptors.
+    pub fds_received: u64,
+    /// Number of payloads that could not be deserialized.
+    pub decode_errors: u64,
+    /// Number of received messages that violated the protocol.
+    pub protocol_errors: u64,
+    /// Number of received messages that were dropped by a limit.
+    pub dropped: u64,
+    /// Number of bytes in the send queue.
+    pub queued: u64,
+    /// Maximum number of bytes in the send queue.
+    pub max_queued: u64,
+    /// Time until sent messages were written or queued below the
+    /// high-water mark.
+    pub send_latency: Histogram,
+    /// Round-trip time of RPC calls.
+    pub call_latency: Histogram,
+}
+
+impl AddAssign for Metrics {
+    fn add_assign(&mut self, other: Self) {
+        self.messages_sent += other.messages_sent;
+        self.messages_received += other.messages_received;
+        self.bytes_sent += other.bytes_sent;
+        self.bytes_received += other.bytes_received;
+        self.fds_sent += other.fds_sent;
+        self.fds_received += other.fds_received;
+        self.decode_errors += other.decode_errors;
+        self.protocol_errors += other.protocol_errors;
+        self.dropped += other.dropped;
+        self.queued += other.queued;
+        self.max_queued = self.max_queued.max(other.max_queued);
+        self.send_latency += other.send_latency;
+        self.call_latency += other.call_latency;
+    }
+}
+
+impl std::iter::Sum for Metrics {
+    fn sum<I: Iterator<Item = Self>>(iter: I) -> Self {
+        iter.fold(Self::default(), |mut sum, metrics| {
+            sum += metrics;
+            sum
+        })
+    }
+}
+
+/// Latency histogram with exponential buckets.
+///
+/// Bucket `i` counts the durations below 2^i microseconds that did
+/// not fit into a lower bucket; the last bucket counts all longer
+/// durations.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Histogram {
+    /// Number of durations per bucket.
+    pub buckets: [u64; Histogram::BUCKETS],
+    /// Total number of durations.
+    pub count: u64,
+    /// Sum of all durations.
+    pub sum: Duration,
+}
+
+impl Histogram {
+    /// Number of buckets.
+    pub const BUCKETS: usize = 32;
+
+    /// Return the upper bound of the bucket.
+    pub fn bucket_bound(index: usize) -> Duration {
+        if index + 1 >= Self::BUCKETS {
+            Duration::MAX
+        } else {
+            Duration::from_micros(1 << index)
+        }
+    }
+
+    /// Return the mean duration.
+    pub fn mean(&self) -> Option<Duration> {
+        (self.count > 0)
+            .then(|| Duration::from_micros((self.sum.as_micros() / self.count as u128) as u64))
+    }
+
+    /// Return the upper bound of the bucket that contains the quantile.
+    pub fn quantile(&self, quantile: f64) -> Option<Duration> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = ((self.count as f64 * quantile).ceil() as u64).max(1);
+        let mut total = 0;
+        self.buckets
+            .iter()
+            .position(|count| {
+                total += count;
+                total >= rank
+            })
+            .map(Self::bucket_bound)
+    }
+}
+
+impl AddAssign for Histogram {
+    fn add_assign(&mut self, other: Self) {
+        for (bucket, count) in self.buckets.iter_mut().zip(other.buckets) {
+            *bucket += count;
+        }
+        self.count += other.count;
+        self.sum += other.sum;
+    }
+}
+
+/// Counters of a handler.
+#[derive(Debug, Default)]
+pub(crate) struct Counters {
+    pub(crate) messages_sent: AtomicU64,
+    pub(crate) messages_received: AtomicU64,
+    pub(crate) bytes_sent: AtomicU64,
+    pub(crate) bytes_received: AtomicU64,
+    pub(crate) fds_sent: AtomicU64,
+    pub(crate) fds_received: AtomicU64,
+    pub(crate) decode_errors: AtomicU64,
+    pub(crate) protocol_errors: AtomicU64,
+    pub(crate) dropped: AtomicU64,
+    pub(crate) max_queued: AtomicU64,
+    pub(crate) send_latency: Latency,
+    pub(crate) call_latency: Latency,
+}
+
+impl Counters {
+    /// Increment the counter.
+    pub(crate) fn add(counter: &AtomicU64, value: usize) {
+        counter.fetch_add(value as u64, Ordering::Relaxed);
+    }
+
+    /// Return a snapshot of the counters.
+    pub(crate) fn snapshot(&self, queued: usize) -> Metrics {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        Metrics {
+            messages_sent: load(&self.messages_sent),
+            messages_received: load(&self.messages_received),
+            bytes_sent: load(&self.bytes_sent),
+            bytes_received: load(&self.bytes_received),
+            fds_sent: load(&self.fds_sent),
+            fds_received: load(&self.fds_received),
+            decode_errors: load(&self.decode_errors),
+            protocol_errors: load(&self.protocol_errors),
+            dropped: load(&self.dropped),
+            queued: queued as u64,
+            max_queued: load(&self.max_queued),
+            send_latency: self.send_latency.snapshot(),
+            call_latency: self.call_latency.snapshot(),
+        }
+    }
+}
+
+/// Atomic latency histogram.
+#[derive(Debug, Default)]
+pub(crate) struct Latency {
+    buckets: [AtomicU64; Histogram::BUCKETS],
+    count: AtomicU64,
+    sum: AtomicU64,
+}
+
+impl Latency {
+    /// Add a duration to the histogram.
+    pub(crate) fn record(&self, duration: Duration) {
+        let micros = u64::try_from(duration.as_micros()).unwrap_or(u64::MAX);
+        let index = (u64::BITS - micros.leading_zeros()) as usize;
+        self.buckets[index.min(Histogram::BUCKETS - 1)].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(micros, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> Histogram {
+        let mut histogram = Histogram {
+            count: self.count.load(Ordering::Relaxed),
+            sum: Duration::from_micros(self.sum.load(Ordering::Relaxed)),
+            ..Default::default()
+        };
+        for (bucket, count) in histogram.buckets.iter_mut().zip(&self.buckets) {
+            *bucket = count.load(Ordering::Relaxed);
+        }
+        histogram
+    }
+}
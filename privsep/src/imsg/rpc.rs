@@ -0,0 +1,256 @@
+//! Request/response RPC over `imsg` channels.
+//!
+//! Each call is tagged with a correlation ID so that several calls
+//! can be in flight on the same channel at once.  The RPC layer owns
+//! the receiving side of the channel: all messages that are sent to
+//! it must be RPC requests or responses.
+//!
+//! Receive errors are delivered to the calls or requests that they
+//! concern, not to the task that happened to read the message.  A
+//! response payload that cannot be decoded only fails its own call.
+//! A response envelope that cannot be decoded has no usable call ID,
+//! so it fails all pending calls.  Transport and protocol errors of
+//! the channel fail all pending calls and are also returned by the
+//! next `recv_request`.  Invalid requests and messages that are
+//! neither requests nor responses are only returned by the next
+//! `recv_request`.
+
+use crate::{
+    error::Error,
+    imsg::{
+        codec,
+        demux::{copy_error, Demux},
+        Codec, Handler, Message, Received,
+    },
+};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::time::{timeout, Instant};
+
+/// Payload of RPC requests and responses.
+#[derive(Debug, serde_derive::Deserialize, serde_derive::Serialize)]
+struct Envelope<T> {
+    /// Correlation ID of the call.
+    call_id: u32,
+    /// The serialized request or response.
+    data: T,
+}
+
+/// Incoming RPC request.
+#[derive(Debug)]
+pub struct Request {
+    /// The message header of the request.
+    pub message: Message,
+    /// Correlation ID of the call.
+    call_id: u32,
+    /// The serialized request.
+    data: Vec<u8>,
+    /// The codec of the handler.
+    codec: Arc<dyn Codec>,
+}
+
+impl Request {
+    /// Deserialize the request.
+    pub fn decode<T: DeserializeOwned>(&self) -> io::Result<T> {
+        codec::decode(&*self.codec, &self.data)
+    }
+}
+
+/// Response of the remote end or the error that was received instead.
+type Response = io::Result<Result<Vec<u8>, String>>;
+
+/// Received requests and responses.
+#[derive(Debug, Default)]
+struct State {
+    /// Pending calls and their responses, if received.
+    calls: HashMap<u32, Option<Response>>,
+    /// Received requests or errors.
+    requests: VecDeque<io::Result<Request>>,
+    /// Set after the channel was closed.
+    closed: bool,
+}
+
+/// RPC endpoint on top of an `imsg` handler.
+///
+/// The endpoint can send calls and serve requests at the same time.
+#[derive(Debug)]
+pub struct Rpc<'a> {
+    /// The underlying `imsg` handler.
+    handler: &'a Handler,
+    /// Next correlation ID.
+    next_id: AtomicU32,
+    /// Received requests and responses.
+    demux: Demux<State>,
+}
+
+impl<'a> Rpc<'a> {
+    /// Create a new RPC endpoint.
+    pub fn new(handler: &'a Handler) -> Self {
+        Self {
+            handler,
+            next_id: AtomicU32::new(1),
+            demux: Default::default(),
+        }
+    }
+
+    /// Call the remote end and wait for the response.
+    ///
+    /// Errors that are returned by the remote end are reported as
+    /// `Error::RemoteError`; a call that does not get a response
+    /// within the timeout returns `TimedOut`.
+    pub async fn call<Req: Serialize, Resp: DeserializeOwned>(
+        &self,
+        message: Message,
+        request: &Req,
+        duration: Duration,
+    ) -> Result<Resp, Error> {
+        self.handler.check_id(&message)?;
+
+        let call_id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.demux.state.lock().calls.insert(call_id, None);
+
+        let start = Instant::now();
+        let result = timeout(duration, self.call_internal(call_id, message, request)).await;
+        if let Ok(Ok(_)) | Ok(Err(Error::RemoteError(_))) = result {
+            self.handler.counters.call_latency.record(start.elapsed());
+        }
+
+        // Remove the call if it failed or timed out.
+        self.demux.state.lock().calls.remove(&call_id);
+
+        let response = result.map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+        self.handler.decode(&response).map_err(Into::into)
+    }
+
+    async fn call_internal<Req: Serialize>(
+        &self,
+        call_id: u32,
+        mut message: Message,
+        request: &Req,
+    ) -> Result<Vec<u8>, Error> {
+        let data = Envelope {
+            call_id,
+            data: self.handler.encode(request)?,
+        };
+        message.flags |= Message::FLAG_REQUEST;
+        self.handler
+            .send_data_internal(message, &[], &self.handler.encode(&data)?)
+            .await?;
+
+        self.wait(|state| state.calls.get_mut(&call_id).and_then(Option::take))
+            .await??
+            .map_err(Error::RemoteError)
+    }
+
+    /// Receive the next request from the remote end.
+    ///
+    /// Returns an error for an invalid request or a message that is
+    /// not an RPC request or response; the channel remains usable.
+    pub async fn recv_request(&self) -> Result<Option<Request>, Error> {
+        match self.wait(|state| state.requests.pop_front()).await {
+            Ok(request) => Ok(Some(request?)),
+            Err(Error::Terminated(_)) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Send the response or an error to the remote end.
+    pub async fn respond<T: Serialize>(
+        &self,
+        request: &Request,
+        response: Result<T, String>,
+    ) -> Result<(), Error> {
+        let data = Envelope {
+            call_id: request.call_id,
+            data: match response {
+                Ok(response) => Ok(self.handler.encode(&response)?),
+                Err(err) => Err(err),
+            },
+        };
+        let message = Message {
+            flags: Message::FLAG_RESPONSE,
+            ..Message::new(request.message.id)
+        };
+        self.handler
+            .send_data_internal(message, &[], &self.handler.encode(&data)?)
+            .await
+            .map_err(Into::into)
+    }
+
+    /// Wait until `ready` returns a value.
+    async fn wait<T>(&self, mut ready: impl FnMut(&mut State) -> Option<T>) -> Result<T, Error> {
+        self.demux
+            .wait(
+                self.handler,
+                |state| match ready(state) {
+                    Some(result) => Some(Ok(result)),
+                    None if state.closed => Some(Err(Error::Terminated("RPC channel"))),
+                    None => None,
+                },
+                |state, frame| self.route(state, frame),
+            )
+            .await
+    }
+
+    /// Store a received request or response.
+    fn route(&self, state: &mut State, frame: io::Result<Option<Received>>) {
+        let Received { message, data, .. } = match frame {
+            Ok(Some(frame)) => frame,
+            Ok(None) => {
+                state.closed = true;
+                return;
+            }
+            Err(err) => {
+                // The lost message might belong to any call or request.
+                for call in state.calls.values_mut().filter(|call| call.is_none()) {
+                    *call = Some(Err(copy_error(&err)));
+                }
+                state.requests.push_back(Err(err));
+                return;
+            }
+        };
+
+        if message.flags & Message::FLAG_RESPONSE != 0 {
+            match self
+                .handler
+                .decode::<Envelope<Result<Vec<u8>, String>>>(&data)
+            {
+                Ok(response) => {
+                    // Drop responses of calls that timed out.
+                    if let Some(call) = state.calls.get_mut(&response.call_id) {
+                        *call = Some(Ok(response.data));
+                    }
+                }
+                Err(err) => {
+                    for call in state.calls.values_mut().filter(|call| call.is_none()) {
+                        *call = Some(Err(copy_error(&err)));
+                    }
+                }
+            }
+        } else if message.flags & Message::FLAG_REQUEST != 0 {
+            let request = self
+                .handler
+                .decode::<Envelope<Vec<u8>>>(&data)
+                .map(|request| Request {
+                    message,
+                    call_id: request.call_id,
+                    data: request.data,
+                    codec: self.handler.options().codec.clone(),
+                });
+            state.requests.push_back(request);
+        } else {
+            state.requests.push_back(Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unexpected non-RPC message",
+            )));
+        }
+    }
+}
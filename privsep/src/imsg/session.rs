@@ -0,0 +1,399 @@
+//! Multiplexing of logical sessions over an `imsg` channel.
+//!
+//! Each message of a session carries the session ID in the `peer_id`
+//! of the header.  Both ends can open sessions at the same time
+//! without coordination: the ID is allocated by the end that opened
+//! the session and the other end sets the `Session::REMOTE` bit when
+//! it sends messages of that session.
+//!
+//! Like the RPC layer, the multiplexer owns the receiving side of the
+//! channel: all messages that are sent to it must belong to a session.
+//! The queued messages of all sessions are limited by the
+//! `Options::max_buffered` of the handler.
+//!
+//! A receive error cannot be assigned to a session, so it is returned
+//! by the next receive of every open session.  If the channel cannot
+//! receive any more messages, all further calls return the error.
+
+use crate::{
+    imsg::{
+        demux::{copy_error, Demux},
+        metrics::Counters,
+        Handler, Limit, Message, Received,
+    },
+    net::Fd,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{self, Result},
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+/// Key of a session: whether it was opened locally, and the ID.
+type Key = (bool, u32);
+
+/// Received messages of a session.
+#[derive(Debug, Default)]
+struct Queue {
+    /// Messages that were not received by the session yet.
+    messages: VecDeque<Received>,
+    /// Set after the remote end closed the session.
+    closed: bool,
+    /// Receive error that was not returned to the session yet.
+    error: Option<io::Error>,
+}
+
+/// Sessions and received messages.
+#[derive(Debug, Default)]
+struct State {
+    /// Receive queues of the open sessions.
+    sessions: HashMap<Key, Queue>,
+    /// Sessions that were opened by the remote end and not accepted.
+    accept: VecDeque<u32>,
+    /// Number of payload bytes in the receive queues.
+    buffered: usize,
+    /// Error that stopped receiving, returned by every wait.
+    error: Option<io::Error>,
+    /// Set after the channel was closed.
+    closed: bool,
+}
+
+/// Session multiplexer on top of an `imsg` handler.
+#[derive(Debug)]
+pub struct Mux<'a> {
+    /// The underlying `imsg` handler.
+    handler: &'a Handler,
+    /// Next ID of a locally opened session.
+    next_id: AtomicU32,
+    /// Open sessions and their receive queues.
+    demux: Demux<State>,
+}
+
+impl<'a> Mux<'a> {
+    /// Maximum number of sessions that wait to be accepted.
+    ///
+    /// Additional sessions of the remote end are closed immediately.
+    pub const BACKLOG: usize = 128;
+
+    /// Create a new session multiplexer.
+    ///
+    /// The handler accepts the reserved session messages from the
+    /// peer until the multiplexer is dropped.
+    pub fn new(handler: &'a Handler) -> Self {
+        handler.accept_reserved(Message::SESSION_OPEN, true);
+        handler.accept_reserved(Message::SESSION_CLOSE, true);
+        Self {
+            handler,
+            next_id: AtomicU32::new(1),
+            demux: Default::default(),
+        }
+    }
+
+    /// Open a new session to the remote end.
+    pub async fn open(&self) -> Result<Session<'_, 'a>> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        if id & Session::REMOTE != 0 {
+            return Err(io::Error::other("session IDs exhausted"));
+        }
+        self.demux
+            .state
+            .lock()
+            .sessions
+            .insert((true, id), Queue::default());
+
+        let session = Session {
+            mux: self,
+            key: (true, id),
+        };
+        self.handler
+            .send_data_internal(session.header(Message::SESSION_OPEN), &[], &[])
+            .await?;
+        Ok(session)
+    }
+
+    /// Wait for the next session that was opened by the remote end.
+    ///
+    /// This returns `None` after the channel was closed.
+    pub async fn accept(&self) -> Result<Option<Session<'_, 'a>>> {
+        let id = match self.wait(|state| state.accept.pop_front()).await? {
+            Some(id) => id,
+            None => return Ok(None),
+        };
+        Ok(Some(Session {
+            mux: self,
+            key: (false, id),
+        }))
+    }
+
+    /// Write the queued messages to the remote end.
+    ///
+    /// A dropped session queues its close without waiting, so the
+    /// remote end might only see it after this or the next send.
+    pub async fn flush(&self) -> Result<()> {
+        self.handler.flush().await
+    }
+
+    /// Wait until `ready` returns a value or the channel is closed.
+    async fn wait<T>(&self, mut ready: impl FnMut(&mut State) -> Option<T>) -> Result<Option<T>> {
+        self.demux
+            .wait(
+                self.handler,
+                |state| match ready(state) {
+                    Some(result) => Some(Ok(Some(result))),
+                    None => match state.error {
+                        Some(ref err) => Some(Err(copy_error(err))),
+                        None if state.closed => Some(Ok(None)),
+                        None => None,
+                    },
+                },
+                |state, received| {
+                    if let Err(err) = self.route(state, received) {
+                        self.fail(state, err);
+                    }
+                },
+            )
+            .await
+    }
+
+    /// Deliver a receive error to all open sessions.
+    ///
+    /// The multiplexer fails if the channel cannot receive any more
+    /// messages.
+    fn fail(&self, state: &mut State, err: io::Error) {
+        for queue in state.sessions.values_mut() {
+            queue.error = Some(copy_error(&err));
+        }
+        if !self.handler.is_recoverable(&err) {
+            state.error = Some(err);
+        }
+    }
+
+    /// Store a received message in the queue of its session.
+    fn route(&self, state: &mut State, received: Result<Option<Received>>) -> Result<()> {
+        let received = match received? {
+            Some(received) => received,
+            None => {
+                state.closed = true;
+                for queue in state.sessions.values_mut() {
+                    queue.closed = true;
+                }
+                return Ok(());
+            }
+        };
+
+        let peer_id = received.message.peer_id;
+        let key = (peer_id & Session::REMOTE != 0, peer_id & !Session::REMOTE);
+        if key.1 == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "message without session",
+            ));
+        }
+
+        match received.message.id {
+            Message::SESSION_OPEN if !key.0 => {
+                if state.sessions.contains_key(&key) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "session already open",
+                    ));
+                }
+                if state.accept.len() >= Self::BACKLOG {
+                    let message = Message {
+                        peer_id: key.1 | Session::REMOTE,
+                        ..Message::new(Message::SESSION_CLOSE)
+                    };
+                    return self.handler.send_data_nowait(message, &[], &[]);
+                }
+                state.sessions.insert(key, Queue::default());
+                state.accept.push_back(key.1);
+            }
+            Message::SESSION_OPEN => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "invalid session ID",
+                ))
+            }
+            Message::SESSION_CLOSE => {
+                if let Some(queue) = state.sessions.get_mut(&key) {
+                    queue.closed = true;
+                }
+            }
+            // Drop messages of sessions that were closed locally.
+            _ => {
+                if let Some(queue) = state.sessions.get_mut(&key) {
+                    let length = received.data.len();
+                    if state.buffered + length > self.handler.options.max_buffered {
+                        Counters::add(&self.handler.counters.dropped, 1);
+                        return self.handler.limit_exceeded(Limit::Buffered);
+                    }
+                    state.buffered += length;
+                    queue.messages.push_back(received);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for Mux<'_> {
+    fn drop(&mut self) {
+        self.handler.accept_reserved(Message::SESSION_OPEN, false);
+        self.handler.accept_reserved(Message::SESSION_CLOSE, false);
+    }
+}
+
+/// Logical session of a `Mux`.
+///
+/// Dropping the session closes it; messages that are still queued
+/// or received afterwards are discarded.  The close is queued without
+/// waiting, use `close` or `Mux::flush` to make sure that it is
+/// written to the remote end.
+#[derive(Debug)]
+pub struct Session<'m, 'a> {
+    mux: &'m Mux<'a>,
+    key: Key,
+}
+
+impl Session<'_, '_> {
+    /// Bit of the session ID that is set by the end that did not
+    /// open the session.
+    pub const REMOTE: u32 = 0x8000_0000;
+
+    /// Return the session ID that is sent in the `peer_id`.
+    pub fn id(&self) -> u32 {
+        match self.key {
+            (true, id) => id,
+            (false, id) => id | Self::REMOTE,
+        }
+    }
+
+    fn header(&self, id: u32) -> Message {
+        Message {
+            peer_id: self.id(),
+            ..Message::new(id)
+        }
+    }
+
+    /// Send a message of the session to the remote end.
+    ///
+    /// The `peer_id` of the message is replaced by the session ID.
+    pub async fn send_message<T: Serialize>(
+        &self,
+        message: Message,
+        fd: Option<&Fd>,
+        data: &T,
+    ) -> Result<()> {
+        let handler = self.mux.handler;
+        handler.check_id(&message)?;
+        let message = Message {
+            peer_id: self.id(),
+            ..message
+        };
+        let data = handler.encode(data)?;
+        handler
+            .send_data_internal(message, fd.as_slice(), &data)
+            .await
+    }
+
+    /// Receive the next message of the session.
+    ///
+    /// This returns `None` after the session or the channel was
+    /// closed by the remote end and all queued messages were received.
+    /// Receive errors of the channel are returned after the messages
+    /// that were queued before them.
+    pub async fn recv_message<T: DeserializeOwned>(
+        &self,
+    ) -> Result<Option<(Message, Option<Fd>, T)>> {
+        let key = self.key;
+        let received = self
+            .mux
+            .wait(|state| {
+                let queue = state.sessions.get_mut(&key)?;
+                match queue.messages.pop_front() {
+                    Some(received) => {
+                        state.buffered -= received.data.len();
+                        Some(Ok(Some(received)))
+                    }
+                    None => match queue.error.take() {
+                        Some(err) => Some(Err(err)),
+                        None if queue.closed => Some(Ok(None)),
+                        None => None,
+                    },
+                }
+            })
+            .await?
+            .transpose()?
+            .flatten();
+
+        match received {
+            Some(Received {
+                message, fds, data, ..
+            }) => {
+                let data = self.mux.handler.decode(&data)?;
+                Ok(Some((message, fds.into_iter().next(), data)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Wait until the remote end closed the session or the channel.
+    ///
+    /// Messages that are received in the meantime stay queued, but a
+    /// receive error is returned.
+    pub async fn closed(&self) -> Result<()> {
+        let key = self.key;
+        self.mux
+            .wait(|state| {
+                let queue = state.sessions.get_mut(&key)?;
+                match queue.error.take() {
+                    Some(err) => Some(Err(err)),
+                    None => queue.closed.then_some(Ok(())),
+                }
+            })
+            .await?
+            .unwrap_or(Ok(()))
+    }
+
+    /// Close the session and notify the remote end.
+    ///
+    /// This waits until the close and all messages before it were
+    /// written to the remote end.
+    pub async fn close(self) -> Result<()> {
+        let handler = self.mux.handler;
+        let message = self.header(Message::SESSION_CLOSE);
+        let closed = self.remove();
+        std::mem::forget(self);
+        if !closed {
+            handler.send_data_internal(message, &[], &[]).await?;
+            handler.flush().await?;
+        }
+        Ok(())
+    }
+
+    /// Remove the session and return whether the remote closed it.
+    fn remove(&self) -> bool {
+        let mut state = self.mux.demux.state.lock();
+        let queue = match state.sessions.remove(&self.key) {
+            Some(queue) => queue,
+            None => return true,
+        };
+        state.buffered -= queue
+            .messages
+            .iter()
+            .map(|received| received.data.len())
+            .sum::<usize>();
+        state.closed || queue.closed
+    }
+}
+
+impl Drop for Session<'_, '_> {
+    fn drop(&mut self) {
+        if !self.remove() {
+            let message = self.header(Message::SESSION_CLOSE);
+            let _ = self.mux.handler.send_data_nowait(message, &[], &[]);
+        }
+    }
+}
@@ -0,0 +1,183 @@
+//! Independent reader and writer halves of an `imsg` handler.
+//!
+//! `Handler::split` hands out a single `ImsgReader` that owns the
+//! receiving side and an `ImsgWriter` that can be cloned into every
+//! task that sends messages.
+
+use crate::{
+    imsg::{Credentials, Handler, Imsg, Message, Metrics, Options},
+    net::{Fd, TypedFd},
+};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{io::Result, sync::Arc};
+use zerocopy::{AsBytes, FromBytes};
+
+/// Receiving half of an `imsg` handler.
+///
+/// All receive methods are cancel safe.
+#[derive(Debug)]
+pub struct ImsgReader {
+    handler: Arc<Handler>,
+}
+
+impl ImsgReader {
+    /// Receive message from the remote end.
+    pub async fn recv_message<T: DeserializeOwned>(
+        &mut self,
+    ) -> Result<Option<(Message, Option<Fd>, T)>> {
+        self.handler.recv_message().await
+    }
+
+    /// Receive message with all passed file descriptors from the remote end.
+    pub async fn recv_message_with_fds<T: DeserializeOwned>(
+        &mut self,
+    ) -> Result<Option<(Message, Vec<Fd>, T)>> {
+        self.handler.recv_message_with_fds().await
+    }
+
+    /// Receive message with file descriptors of a verified kind.
+    pub async fn recv_message_with_typed_fds<T: DeserializeOwned>(
+        &mut self,
+    ) -> Result<Option<(Message, Vec<TypedFd>, T)>> {
+        self.handler.recv_message_with_typed_fds().await
+    }
+
+    /// Receive message with the credentials of the sending process.
+    pub async fn recv_message_with_credentials<T: DeserializeOwned>(
+        &mut self,
+    ) -> Result<Option<(Message, Option<Fd>, T, Option<Credentials>)>> {
+        self.handler.recv_message_with_credentials().await
+    }
+
+    /// Receive a fixed-layout struct without deserializing it.
+    pub async fn recv_raw<T: FromBytes>(&mut self) -> Result<Option<(Message, Option<Fd>, T)>> {
+        self.handler.recv_raw().await
+    }
+
+    /// Receive a typed message from the remote end.
+    pub async fn recv_imsg<M: Imsg>(&mut self) -> Result<Option<(Message, Option<Fd>, M)>> {
+        self.handler.recv_imsg().await
+    }
+
+    /// Return the handler options.
+    pub fn options(&self) -> &Options {
+        self.handler.options()
+    }
+
+    /// Return a snapshot of the handler metrics.
+    pub fn metrics(&self) -> Metrics {
+        self.handler.metrics()
+    }
+
+    /// Put the reader and a writer of the same handler back together.
+    ///
+    /// This fails and returns both halves if they belong to
+    /// different handlers or if other writers are still alive.
+    pub fn reunite(self, writer: ImsgWriter) -> std::result::Result<Handler, (Self, ImsgWriter)> {
+        if !Arc::ptr_eq(&self.handler, &writer.handler) {
+            return Err((self, writer));
+        }
+        drop(writer);
+        Arc::try_unwrap(self.handler).map_err(|handler| {
+            (
+                Self {
+                    handler: handler.clone(),
+                },
+                ImsgWriter { handler },
+            )
+        })
+    }
+}
+
+/// Sending half of an `imsg` handler.
+///
+/// The writer can be cloned to send messages from multiple tasks.
+#[derive(Clone, Debug)]
+pub struct ImsgWriter {
+    pub(super) handler: Arc<Handler>,
+}
+
+impl ImsgWriter {
+    /// Send message to remote end.
+    pub async fn send_message<T: Serialize>(
+        &self,
+        message: Message,
+        fd: Option<&Fd>,
+        data: &T,
+    ) -> Result<()> {
+        self.handler.send_message(message, fd, data).await
+    }
+
+    /// Send message with a list of file descriptors to the remote end.
+    pub async fn send_message_with_fds<T: Serialize>(
+        &self,
+        message: Message,
+        fds: &[&Fd],
+        data: &T,
+    ) -> Result<()> {
+        self.handler.send_message_with_fds(message, fds, data).await
+    }
+
+    /// Send message with file descriptors that are tagged with their kind.
+    pub async fn send_message_with_typed_fds<T: Serialize>(
+        &self,
+        message: Message,
+        fds: &[&Fd],
+        data: &T,
+    ) -> Result<()> {
+        self.handler
+            .send_message_with_typed_fds(message, fds, data)
+            .await
+    }
+
+    /// Send a fixed-layout struct without serializing it.
+    pub async fn send_raw<T: AsBytes + ?Sized>(
+        &self,
+        message: Message,
+        fd: Option<&Fd>,
+        data: &T,
+    ) -> Result<()> {
+        self.handler.send_raw(message, fd, data).await
+    }
+
+    /// Send a typed message to the remote end.
+    pub async fn send_imsg<M: Imsg>(&self, imsg: &M, fd: Option<&Fd>) -> Result<()> {
+        self.handler.send_imsg(imsg, fd).await
+    }
+
+    /// Write all queued messages to the socket.
+    pub async fn flush(&self) -> Result<()> {
+        self.handler.flush().await
+    }
+
+    /// Return the number of bytes in the send queue.
+    pub fn queued(&self) -> usize {
+        self.handler.queued()
+    }
+
+    /// Return the handler options.
+    pub fn options(&self) -> &Options {
+        self.handler.options()
+    }
+
+    /// Return a snapshot of the handler metrics.
+    pub fn metrics(&self) -> Metrics {
+        self.handler.metrics()
+    }
+}
+
+impl Handler {
+    /// Split the handler into a reader and a cloneable writer.
+    ///
+    /// Exactly one task owns the reader while any number of tasks
+    /// can send messages with clones of the writer.
+    pub fn split(self) -> (ImsgReader, ImsgWriter) {
+        let handler = Arc::new(self);
+        (
+            ImsgReader {
+                handler: handler.clone(),
+            },
+            ImsgWriter { handler },
+        )
+    }
+}
@@ -0,0 +1,142 @@
+//! `futures` adapters of the split `imsg` handler halves.
+
+use crate::{
+    imsg::{ImsgReader, ImsgWriter, Message},
+    net::Fd,
+};
+use futures::{
+    future::BoxFuture,
+    sink::Sink,
+    stream::{self, BoxStream, Stream},
+    FutureExt,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    fmt,
+    io::Result,
+    marker::PhantomData,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// Stream of received messages.
+pub struct ImsgStream<T> {
+    inner: BoxStream<'static, Result<(Message, Option<Fd>, T)>>,
+}
+
+impl<T> fmt::Debug for ImsgStream<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ImsgStream").finish()
+    }
+}
+
+impl<T> Stream for ImsgStream<T> {
+    type Item = Result<(Message, Option<Fd>, T)>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.inner.as_mut().poll_next(cx)
+    }
+}
+
+impl ImsgReader {
+    /// Turn the reader into a stream of messages.
+    ///
+    /// The stream ends when the remote end closed the channel.
+    pub fn into_stream<T: DeserializeOwned + Send + 'static>(self) -> ImsgStream<T> {
+        let inner = stream::try_unfold(self, |mut reader| async move {
+            Ok(reader
+                .recv_message()
+                .await?
+                .map(|received| (received, reader)))
+        });
+        ImsgStream {
+            inner: Box::pin(inner),
+        }
+    }
+}
+
+/// Sink of messages that are sent to the remote end.
+///
+/// The payload is serialized when the message is passed to the sink.
+pub struct ImsgSink<T> {
+    writer: ImsgWriter,
+    /// The pending send or flush operation.
+    pending: Option<BoxFuture<'static, Result<()>>>,
+    _data: PhantomData<fn(T)>,
+}
+
+impl<T> fmt::Debug for ImsgSink<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ImsgSink")
+            .field("writer", &self.writer)
+            .finish()
+    }
+}
+
+impl<T> ImsgSink<T> {
+    /// Drive the pending operation to completion.
+    fn poll_pending(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        if let Some(pending) = self.pending.as_mut() {
+            let result = futures::ready!(pending.poll_unpin(cx));
+            self.pending = None;
+            result?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T: Serialize> Sink<(Message, Option<Fd>, T)> for ImsgSink<T> {
+    type Error = std::io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.get_mut().poll_pending(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: (Message, Option<Fd>, T)) -> Result<()> {
+        let this = self.get_mut();
+        let handler = this.writer.handler.clone();
+        let (message, fd, data) = item;
+        handler.check_id(&message)?;
+        let data = handler.encode(&data)?;
+        this.pending = Some(
+            async move {
+                let fd = fd.as_ref();
+                handler
+                    .send_data_internal(message, fd.as_slice(), &data)
+                    .await
+            }
+            .boxed(),
+        );
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        loop {
+            futures::ready!(this.poll_pending(cx))?;
+            if this.writer.queued() == 0 {
+                break Poll::Ready(Ok(()));
+            }
+            let writer = this.writer.clone();
+            this.pending = Some(async move { writer.flush().await }.boxed());
+        }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.poll_flush(cx)
+    }
+}
+
+impl ImsgWriter {
+    /// Turn the writer into a sink of messages.
+    ///
+    /// Messages are sent with the next `poll_ready` and the queue is
+    /// written completely on `poll_flush`.
+    pub fn into_sink<T: Serialize>(self) -> ImsgSink<T> {
+        ImsgSink {
+            writer: self,
+            pending: None,
+            _data: PhantomData,
+        }
+    }
+}
@@ -5,5 +5,5 @@ mod fd;
 mod stream;
 
 pub use ancillary::{AncillaryData, SocketAncillary};
-pub use fd::Fd;
-pub use stream::{StdUnixStreamExt, UnixStream, UnixStreamExt};
+pub use fd::{Fd, FdKind, FromFd, TypedFd};
+pub use stream::{StdUnixStreamExt, Transport, UnixStream, UnixStreamExt};
@@ -411,12 +411,12 @@ impl<'a> AncillaryData<'a> {
                     let cmsg_len_zero = libc::CMSG_LEN(0) as libc::socklen_t;
                 }
             }
-            let data_len = (*cmsg).cmsg_len - cmsg_len_zero;
+            let data_len = cmsg.cmsg_len - cmsg_len_zero;
             let data = libc::CMSG_DATA(cmsg).cast();
             let data = from_raw_parts(data, data_len as usize);
 
-            match (*cmsg).cmsg_level {
-                libc::SOL_SOCKET => match (*cmsg).cmsg_type {
+            match cmsg.cmsg_level {
+                libc::SOL_SOCKET => match cmsg.cmsg_type {
                     libc::SCM_RIGHTS => Ok(AncillaryData::as_rights(data)),
                     #[cfg(any(target_os = "android", target_os = "linux",))]
                     libc::SCM_CREDENTIALS => Ok(AncillaryData::as_credentials(data)),
@@ -427,7 +427,7 @@ impl<'a> AncillaryData<'a> {
                 },
                 cmsg_level => Err(AncillaryError::Unknown {
                     cmsg_level,
-                    cmsg_type: (*cmsg).cmsg_type,
+                    cmsg_type: cmsg.cmsg_type,
                 }),
             }
         }
@@ -624,7 +624,7 @@ impl<'a> SocketAncillary<'a> {
     pub fn add_fds(&mut self, fds: &[RawFd]) -> bool {
         self.truncated = false;
         add_to_ancillary_data(
-            &mut self.buffer,
+            self.buffer,
             &mut self.length,
             fds,
             libc::SOL_SOCKET,
@@ -643,7 +643,7 @@ impl<'a> SocketAncillary<'a> {
     pub fn add_creds(&mut self, creds: &[SocketCred]) -> bool {
         self.truncated = false;
         add_to_ancillary_data(
-            &mut self.buffer,
+            self.buffer,
             &mut self.length,
             creds,
             libc::SOL_SOCKET,
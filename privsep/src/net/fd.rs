@@ -1,16 +1,30 @@
 //! Owned, droppable file descriptors.
+//!
+//! A received `Fd` can be converted into a safe std or tokio object
+//! with `Fd::into_typed`, which verifies the kind of the descriptor
+//! with `fstat` and `getsockopt` first.
 
-use crate::error::Error;
-use derive_more::{From, Into};
+use crate::{error::Error, imsg::ProtocolError};
+use derive_more::{Display, From, Into};
 use nix::{
     fcntl::{fcntl, FcntlArg},
+    sys::{
+        socket::{getsockname, getsockopt, sockopt, SockAddr, SockType},
+        stat::fstat,
+    },
     unistd::{close, dup},
 };
+use serde_derive::{Deserialize, Serialize};
 use std::{
+    fs,
     io::{self},
-    mem,
-    os::unix::io::{AsRawFd, IntoRawFd, RawFd},
+    mem, net,
+    os::unix::{
+        io::{AsRawFd, FromRawFd, IntoRawFd, RawFd},
+        net as unix,
+    },
 };
+use tokio::net as tokio_net;
 
 /// Wrapper for `RawFd` that closes the file descriptor when dropped.
 #[derive(Debug, From, Into)]
@@ -28,6 +42,11 @@ impl Fd {
             .map(|_| ())
             .map_err(|err| io::Error::new(io::ErrorKind::NotConnected, err).into())
     }
+
+    /// Convert the file descriptor into an object of its kind.
+    pub fn into_typed<T: FromFd>(self) -> Result<T, Error> {
+        TypedFd::new(self)?.into_typed()
+    }
 }
 
 impl Drop for Fd {
@@ -49,3 +68,161 @@ impl AsRawFd for Fd {
         self.0
     }
 }
+
+/// Kind of a passed file descriptor.
+#[derive(Clone, Copy, Debug, Display, PartialEq, Eq, Deserialize, Serialize)]
+pub enum FdKind {
+    /// A regular file or a character device.
+    #[display(fmt = "file")]
+    File,
+    /// A listening TCP socket.
+    #[display(fmt = "TCP listener")]
+    TcpListener,
+    /// A connected or unconnected TCP socket.
+    #[display(fmt = "TCP stream")]
+    TcpStream,
+    /// A UDP socket.
+    #[display(fmt = "UDP socket")]
+    UdpSocket,
+    /// A listening UNIX stream socket.
+    #[display(fmt = "UNIX listener")]
+    UnixListener,
+    /// A UNIX stream socket.
+    #[display(fmt = "UNIX stream")]
+    UnixStream,
+    /// A UNIX datagram socket.
+    #[display(fmt = "UNIX datagram")]
+    UnixDatagram,
+    /// A UNIX sequenced-packet socket, e.g. of `Transport::SeqPacket`.
+    #[display(fmt = "UNIX seqpacket")]
+    UnixSeqPacket,
+}
+
+impl FdKind {
+    /// Return the kind of the file descriptor.
+    pub fn of<T: AsRawFd>(fd: &T) -> io::Result<Self> {
+        let fd = fd.as_raw_fd();
+        let unsupported = || {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "unsupported file descriptor",
+            ))
+        };
+
+        match fstat(fd)?.st_mode & libc::S_IFMT {
+            libc::S_IFREG | libc::S_IFCHR => return Ok(Self::File),
+            libc::S_IFSOCK => (),
+            _ => return unsupported(),
+        }
+
+        let inet = match getsockname(fd)? {
+            SockAddr::Inet(_) => true,
+            SockAddr::Unix(_) => false,
+            _ => return unsupported(),
+        };
+        let listening = getsockopt(fd, sockopt::AcceptConn)?;
+        match (inet, getsockopt(fd, sockopt::SockType)?, listening) {
+            (true, SockType::Stream, true) => Ok(Self::TcpListener),
+            (true, SockType::Stream, false) => Ok(Self::TcpStream),
+            (true, SockType::Datagram, _) => Ok(Self::UdpSocket),
+            (false, SockType::Stream, true) => Ok(Self::UnixListener),
+            (false, SockType::Stream, false) => Ok(Self::UnixStream),
+            (false, SockType::Datagram, _) => Ok(Self::UnixDatagram),
+            (false, SockType::SeqPacket, _) => Ok(Self::UnixSeqPacket),
+            _ => unsupported(),
+        }
+    }
+}
+
+/// File descriptor with a verified kind.
+#[derive(Debug)]
+pub struct TypedFd {
+    kind: FdKind,
+    fd: Fd,
+}
+
+impl TypedFd {
+    /// Verify the kind of the file descriptor.
+    pub fn new(fd: Fd) -> io::Result<Self> {
+        let kind = FdKind::of(&fd)?;
+        Ok(Self { kind, fd })
+    }
+
+    /// Return the kind of the file descriptor.
+    pub fn kind(&self) -> FdKind {
+        self.kind
+    }
+
+    /// Convert the file descriptor into an object of its kind.
+    ///
+    /// A descriptor of another kind returns an I/O error with
+    /// `ProtocolError::InvalidFdKind`.  Tokio objects are set to
+    /// nonblocking mode, which also changes the mode of all other
+    /// descriptors of the same socket, e.g. in the sending process.
+    /// The std objects keep the mode of the passed descriptor.
+    pub fn into_typed<T: FromFd>(self) -> Result<T, Error> {
+        if self.kind != T::KIND {
+            return Err(io::Error::from(ProtocolError::InvalidFdKind(T::KIND, self.kind)).into());
+        }
+        T::from_checked_fd(self.fd).map_err(Into::into)
+    }
+}
+
+impl From<TypedFd> for Fd {
+    fn from(fd: TypedFd) -> Self {
+        fd.fd
+    }
+}
+
+impl AsRawFd for TypedFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+/// Object that can be created from a passed file descriptor.
+pub trait FromFd: Sized {
+    /// The kind of the file descriptor.
+    const KIND: FdKind;
+
+    /// Take ownership of a file descriptor that is of `KIND`.
+    ///
+    /// This is called by `TypedFd::into_typed` after the kind was
+    /// verified.
+    fn from_checked_fd(fd: Fd) -> io::Result<Self>;
+}
+
+macro_rules! impl_from_fd {
+    ($kind:ident, $std:ty) => {
+        /// The descriptor keeps its blocking or nonblocking mode.
+        impl FromFd for $std {
+            const KIND: FdKind = FdKind::$kind;
+
+            fn from_checked_fd(fd: Fd) -> io::Result<Self> {
+                Ok(unsafe { Self::from_raw_fd(fd.into_raw_fd()) })
+            }
+        }
+    };
+    ($kind:ident, $std:ty, $tokio:ty) => {
+        impl_from_fd!($kind, $std);
+
+        /// The descriptor is set to nonblocking mode.
+        impl FromFd for $tokio {
+            const KIND: FdKind = FdKind::$kind;
+
+            fn from_checked_fd(fd: Fd) -> io::Result<Self> {
+                let socket = <$std>::from_checked_fd(fd)?;
+                socket.set_nonblocking(true)?;
+                Self::from_std(socket)
+            }
+        }
+    };
+}
+
+impl_from_fd!(File, fs::File);
+impl_from_fd!(TcpListener, net::TcpListener, tokio_net::TcpListener);
+impl_from_fd!(TcpStream, net::TcpStream, tokio_net::TcpStream);
+impl_from_fd!(UdpSocket, net::UdpSocket, tokio_net::UdpSocket);
+impl_from_fd!(UnixListener, unix::UnixListener, tokio_net::UnixListener);
+impl_from_fd!(UnixStream, unix::UnixStream, tokio_net::UnixStream);
+impl_from_fd!(UnixDatagram, unix::UnixDatagram, tokio_net::UnixDatagram);
@@ -1,20 +1,124 @@
 //! `UnixStream` extensions to support file descriptor passing.
+//!
+//! The extensions use `recvmsg` and `sendmsg` directly, so they also
+//! work on a `UnixStream` that wraps a `SOCK_SEQPACKET` socket of a
+//! `Transport::SeqPacket` pair.  Each call then receives or sends
+//! exactly one record with its file descriptors.  Sending an empty
+//! buffer fails with `io::ErrorKind::InvalidInput` because the remote
+//! end would read an empty record as the end of file.
 
 use crate::net::ancillary::{
     recv_vectored_with_ancillary_from, send_vectored_with_ancillary_to, SocketAncillary,
 };
 use async_trait::async_trait;
+use nix::{
+    fcntl::{fcntl, FcntlArg, FdFlag},
+    sys::socket::{self, sockopt, AddressFamily, SockFlag, SockType},
+};
 use std::{
     io::{self, IoSlice, IoSliceMut, Result},
     os::unix::{
-        io::{FromRawFd, RawFd},
+        io::{AsRawFd, FromRawFd, RawFd},
         net as std_net,
     },
 };
-use tokio::{net as tokio_net, task::yield_now};
+use tokio::{io::Interest, net as tokio_net, task::yield_now};
 
 pub use tokio_net::UnixStream;
 
+/// Flag to create sockets with close-on-exec atomically.
+#[cfg(any(
+    target_os = "android",
+    target_os = "dragonfly",
+    target_os = "freebsd",
+    target_os = "illumos",
+    target_os = "linux",
+    target_os = "netbsd",
+    target_os = "openbsd"
+))]
+const CLOEXEC: SockFlag = SockFlag::SOCK_CLOEXEC;
+#[cfg(not(any(
+    target_os = "android",
+    target_os = "dragonfly",
+    target_os = "freebsd",
+    target_os = "illumos",
+    target_os = "linux",
+    target_os = "netbsd",
+    target_os = "openbsd"
+)))]
+const CLOEXEC: SockFlag = SockFlag::empty();
+
+/// Socket type of the connected UNIX sockets of an `imsg` channel.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Transport {
+    /// `SOCK_STREAM`: the messages are a byte stream that is framed
+    /// by the `imsg` headers.
+    #[default]
+    Stream,
+    /// `SOCK_SEQPACKET`: each frame is sent as a single record with
+    /// its file descriptors.
+    ///
+    /// `SOCK_DGRAM` is not supported because a datagram socket does
+    /// not report when the remote end closed the channel.
+    ///
+    /// macOS and iOS do not support `SOCK_SEQPACKET` for UNIX sockets,
+    /// creating a pair fails with `io::ErrorKind::Unsupported` there.
+    SeqPacket,
+}
+
+impl Transport {
+    /// Create a new pair of connected sockets.
+    pub fn pair(self) -> Result<(UnixStream, UnixStream)> {
+        let (a, b) = self.std_pair()?;
+        Ok((UnixStream::from_std(a)?, UnixStream::from_std(b)?))
+    }
+
+    /// Create a new pair of connected, nonblocking sockets that are
+    /// not registered with the async runtime.
+    pub fn std_pair(self) -> Result<(std_net::UnixStream, std_net::UnixStream)> {
+        let (a, b) = match self {
+            Self::Stream => std_net::UnixStream::pair()?,
+            Self::SeqPacket if cfg!(any(target_os = "macos", target_os = "ios")) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "SOCK_SEQPACKET is not supported on this platform",
+                ))
+            }
+            Self::SeqPacket => {
+                let (a, b) =
+                    socket::socketpair(AddressFamily::Unix, SockType::SeqPacket, None, CLOEXEC)?;
+                let pair = unsafe {
+                    (
+                        std_net::UnixStream::from_raw_fd(a),
+                        std_net::UnixStream::from_raw_fd(b),
+                    )
+                };
+                // Set close-on-exec where `SOCK_CLOEXEC` is not available.
+                if CLOEXEC.is_empty() {
+                    fcntl(a, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))?;
+                    fcntl(b, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))?;
+                }
+                pair
+            }
+        };
+        a.set_nonblocking(true)?;
+        b.set_nonblocking(true)?;
+        Ok((a, b))
+    }
+
+    /// Return the transport of a connected socket.
+    pub fn of<T: AsRawFd>(socket: &T) -> Result<Self> {
+        match socket::getsockopt(socket.as_raw_fd(), sockopt::SockType)? {
+            SockType::Stream => Ok(Self::Stream),
+            SockType::SeqPacket => Ok(Self::SeqPacket),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "unsupported socket type",
+            )),
+        }
+    }
+}
+
 #[async_trait]
 pub trait UnixStreamExt {
     async fn recv_vectored_with_ancillary(
@@ -29,6 +133,23 @@ pub trait UnixStreamExt {
         ancillary: &mut SocketAncillary<'_>,
     ) -> Result<usize>;
 
+    /// Try to receive data and ancillary data without waiting.
+    ///
+    /// This returns `WouldBlock` if the socket is not readable and
+    /// clears the readiness so that `readable()` waits for new data.
+    fn try_recv_vectored_with_ancillary(
+        &self,
+        bufs: &mut [IoSliceMut<'_>],
+        ancillary: &mut SocketAncillary<'_>,
+    ) -> Result<usize>;
+
+    /// Try to send data and ancillary data without waiting.
+    fn try_send_vectored_with_ancillary(
+        &self,
+        bufs: &[IoSlice<'_>],
+        ancillary: &mut SocketAncillary<'_>,
+    ) -> Result<usize>;
+
     #[allow(clippy::missing_safety_doc)]
     unsafe fn from_raw_fd(fd: RawFd) -> Result<UnixStream>;
 }
@@ -59,6 +180,7 @@ impl UnixStreamExt for UnixStream {
         bufs: &[IoSlice<'_>],
         ancillary: &mut SocketAncillary<'_>,
     ) -> Result<usize> {
+        check_empty(bufs)?;
         loop {
             self.writable().await?;
 
@@ -73,11 +195,44 @@ impl UnixStreamExt for UnixStream {
         }
     }
 
+    fn try_recv_vectored_with_ancillary(
+        &self,
+        bufs: &mut [IoSliceMut<'_>],
+        ancillary: &mut SocketAncillary<'_>,
+    ) -> Result<usize> {
+        self.try_io(Interest::READABLE, || {
+            recv_vectored_with_ancillary_from(self, bufs, ancillary).map(|(count, _)| count)
+        })
+    }
+
+    fn try_send_vectored_with_ancillary(
+        &self,
+        bufs: &[IoSlice<'_>],
+        ancillary: &mut SocketAncillary<'_>,
+    ) -> Result<usize> {
+        check_empty(bufs)?;
+        self.try_io(Interest::WRITABLE, || {
+            send_vectored_with_ancillary_to(self, bufs, ancillary)
+        })
+    }
+
     unsafe fn from_raw_fd(fd: RawFd) -> Result<Self> {
         Self::from_std(std_net::UnixStream::from_raw_fd(fd))
     }
 }
 
+/// Reject an empty send that would be read as the end of file.
+fn check_empty(bufs: &[IoSlice<'_>]) -> Result<()> {
+    if bufs.iter().all(|buf| buf.is_empty()) {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "empty records are read as end of file",
+        ))
+    } else {
+        Ok(())
+    }
+}
+
 pub trait StdUnixStreamExt {
     fn recv_vectored_with_ancillary(
         &self,
@@ -109,6 +264,7 @@ impl StdUnixStreamExt for std_net::UnixStream {
         bufs: &[IoSlice<'_>],
         ancillary: &mut SocketAncillary<'_>,
     ) -> Result<usize> {
+        check_empty(bufs)?;
         send_vectored_with_ancillary_to(self, bufs, ancillary)
     }
 }
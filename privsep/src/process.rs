@@ -2,11 +2,13 @@
 
 use crate::{
     error::Error,
-    imsg::{Handler, Message},
+    imsg::{Handler, Message, Metrics},
+    net::{Fd, Transport},
 };
 use arrayvec::ArrayVec;
 use close_fds::close_open_fds;
 use derive_more::{AsRef, Deref, Display, From};
+use futures::future::join_all;
 use nix::{
     fcntl::{fcntl, open, FcntlArg, FdFlag, OFlag},
     sys::{
@@ -17,18 +19,30 @@ use nix::{
         self, chdir, chroot, close, dup2, execve, fork, geteuid, setsid, ForkResult, Pid, User,
     },
 };
+use serde::Serialize;
+use sha2::{Digest, Sha256};
 use std::{
     borrow::Cow,
     collections::HashSet,
     env,
     ffi::CString,
-    ops,
+    fs,
+    future::Future,
+    io, ops,
     os::unix::{
         ffi::OsStrExt,
         io::{AsRawFd, RawFd},
     },
-    path::Path,
+    path::{Path, PathBuf},
+    time::Duration,
 };
+use tokio::time::timeout;
+
+mod control;
+mod watchdog;
+
+pub use control::Control;
+pub use watchdog::{Keepalive, WatchdogAction};
 
 /// Internal file descriptor that is passed between processes.
 pub const PRIVSEP_FD: RawFd = libc::STDERR_FILENO + 1;
@@ -36,6 +50,9 @@ pub const PRIVSEP_FD: RawFd = libc::STDERR_FILENO + 1;
 /// Reserved name for the parent process.
 pub const PARENT: &str = "parent";
 
+/// Version of the internal protocol that is checked by the handshake.
+pub const PROTOCOL_VERSION: u32 = 1;
+
 /// Runtime-configurable options for the privsep setup.
 #[derive(Clone, Debug, Default)]
 pub struct Config {
@@ -56,7 +73,7 @@ impl From<&Config> for privsep_log::Config {
 }
 
 /// General options for the privsep setup.
-#[derive(Debug, Default, From)]
+#[derive(Debug, From)]
 pub struct Options {
     /// This stop requiring root and disables privdrop.
     pub disable_privdrop: bool,
@@ -64,6 +81,33 @@ pub struct Options {
     pub username: Cow<'static, str>,
     /// The runtime configuration.
     pub config: Config,
+    /// Identifier of the build that must match on all processes.
+    ///
+    /// The `Privsep` derive sets it to `PRIVSEP_BUILD_ID`, if that is
+    /// set at compile time, or to the `executable_id`, so a child that
+    /// executes a replaced binary fails the handshake.
+    pub build_id: Cow<'static, str>,
+    /// Socket type of the channels between the processes.
+    ///
+    /// The children detect the transport of the inherited channel.
+    pub transport: Transport,
+    /// Time each process waits for the handshake of a peer.
+    ///
+    /// The default is 10 seconds.
+    pub handshake_timeout: Duration,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            disable_privdrop: false,
+            username: Default::default(),
+            config: Default::default(),
+            build_id: Default::default(),
+            transport: Default::default(),
+            handshake_timeout: Duration::from_secs(10),
+        }
+    }
 }
 
 /// Child process startup definition.
@@ -101,6 +145,13 @@ impl Default for Peer {
     }
 }
 
+impl Peer {
+    /// Return a snapshot of the channel metrics, if connected.
+    pub fn metrics(&self) -> Option<Metrics> {
+        self.handler.as_ref().map(Handler::metrics)
+    }
+}
+
 impl ops::Deref for Peer {
     type Target = Handler;
 
@@ -151,7 +202,7 @@ impl<const N: usize> Parent<N> {
                 });
                 continue;
             }
-            let (handler, remote) = Handler::pair()?;
+            let (handler, remote) = Handler::pair_with(options.transport)?;
 
             let pid = match unsafe { fork() }? {
                 ForkResult::Parent { child, .. } => child,
@@ -184,7 +235,7 @@ impl<const N: usize> Parent<N> {
                         env::var("RUST_LOG")
                             .ok()
                             .as_deref()
-                            .or_else(|| options.config.log_level.as_deref())
+                            .or(options.config.log_level.as_deref())
                             .unwrap_or_default()
                     ))
                     .unwrap()];
@@ -207,12 +258,73 @@ impl<const N: usize> Parent<N> {
         // Closing the imsg pipes will terminate the program.
         unsafe { signal(Signal::SIGPIPE, SigHandler::SigIgn) }?;
 
+        // Verify that the children run the same build.
+        let local = Handshake::new(0, PARENT, &options.build_id);
+        let handshakes = children.iter().enumerate().filter_map(|(id, child)| {
+            let handler = child.handler.as_ref()?;
+            Some(handshake(
+                handler,
+                &local,
+                id,
+                child.name,
+                options.handshake_timeout,
+            ))
+        });
+        join_all(handshakes)
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // Answer the keepalive requests of the children's watchdogs.
+        for handler in children.iter().filter_map(|child| child.handler.as_ref()) {
+            handler.accept_reserved(Message::PING, true);
+        }
+
         Ok(Self {
             pid: Pid::this(),
             children,
         })
     }
 
+    /// Return the aggregated metrics of the channels to all children.
+    pub fn metrics(&self) -> Metrics {
+        self.children.iter().filter_map(Peer::metrics).sum()
+    }
+
+    /// Send a message to all connected children.
+    ///
+    /// See `multicast` for the fd and error handling.
+    pub async fn broadcast<T: Serialize>(
+        &self,
+        message: Message,
+        fd: Option<&Fd>,
+        data: &T,
+    ) -> Result<(), Error> {
+        multicast(&self.children, None, |handler| {
+            handler.send_message(message, fd, data)
+        })
+        .await
+    }
+
+    /// Send a message to the children with the given IDs.
+    ///
+    /// The message is sent to all children concurrently and each of
+    /// them receives its own duplicate of the fd.  A failure does not
+    /// stop the other sends; `Error::SendFailed` reports the ID and
+    /// error of each child that did not get the message.
+    pub async fn multicast<T: Serialize>(
+        &self,
+        ids: &[usize],
+        message: Message,
+        fd: Option<&Fd>,
+        data: &T,
+    ) -> Result<(), Error> {
+        multicast(&self.children, Some(ids), |handler| {
+            handler.send_message(message, fd, data)
+        })
+        .await
+    }
+
     pub async fn connect(self, processes: [Processes<N>; N]) -> Result<Self, Error> {
         // Filter for bi-directional child-child connections.
         let pairs = processes
@@ -237,13 +349,14 @@ impl<const N: usize> Parent<N> {
             .collect::<HashSet<_>>();
 
         for (a, b) in pairs {
-            let (left, right) = Handler::socketpair()?;
+            // Siblings use the same transport as the parent.
+            let (left, right) = Handler::socketpair_with(self[a].transport())?;
 
             self[a]
-                .send_message_internal(Message::connect(b), Some(&left), &())
+                .send_message_internal(Message::connect(b), &[&left], &())
                 .await?;
             self[b]
-                .send_message_internal(Message::connect(a), Some(&right), &())
+                .send_message_internal(Message::connect(a), &[&right], &())
                 .await?;
         }
 
@@ -277,12 +390,28 @@ impl<const N: usize> Child<N> {
 
         set_cloexec(PRIVSEP_FD, true)?;
 
+        let id = processes
+            .iter()
+            .position(|process| process.name == name)
+            .ok_or_else(|| Error::InvalidProcess(name.into()))?;
+        let local = Handshake::new(id, name, &options.build_id);
+
         let mut peers = Peers::default();
         peers.push(Peer {
             name: processes[0].name,
             handler: Some(Handler::from_raw_fd(PRIVSEP_FD)?),
             ..Peer::default()
         });
+        handshake(
+            &peers[0],
+            &local,
+            0,
+            processes[0].name,
+            options.handshake_timeout,
+        )
+        .await?;
+        // Answer the keepalive requests of the parent's watchdog.
+        peers[0].accept_reserved(Message::PING, true);
         for process in processes.iter().skip(1) {
             peers.push(Peer {
                 name: process.name,
@@ -336,12 +465,22 @@ impl<const N: usize> Child<N> {
             .iter()
             .enumerate()
             .skip(1)
-            .filter_map(|(id, proc)| proc.connect.then(|| id))
+            .filter_map(|(id, proc)| proc.connect.then_some(id))
             .collect::<HashSet<_>>();
 
+        // Only the parent may send connect messages during startup.
+        peers[0].accept_reserved(Message::CONNECT, true);
         while !wait_connections.is_empty() {
             match peers[0].recv_message().await? {
-                Some((Message { id: 1, peer_id, .. }, Some(fd), ())) => {
+                Some((
+                    Message {
+                        id: Message::CONNECT,
+                        peer_id,
+                        ..
+                    },
+                    Some(fd),
+                    (),
+                )) => {
                     let peer_id = peer_id as usize;
                     if !wait_connections.remove(&peer_id) {
                         panic!("Received invalid peer message, terminating");
@@ -353,6 +492,23 @@ impl<const N: usize> Child<N> {
                 _ => panic!("Failed to get peer message, terminating"),
             }
         }
+        peers[0].accept_reserved(Message::CONNECT, false);
+
+        // Verify the identity of the connected siblings.
+        let handshakes = peers.iter().enumerate().skip(1).filter_map(|(id, peer)| {
+            let handler = peer.handler.as_ref()?;
+            Some(handshake(
+                handler,
+                &local,
+                id,
+                peer.name,
+                options.handshake_timeout,
+            ))
+        });
+        join_all(handshakes)
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()?;
 
         Ok(Self {
             name,
@@ -361,6 +517,62 @@ impl<const N: usize> Child<N> {
         })
     }
 
+    /// Send a message to any process.
+    ///
+    /// The message is sent directly if this child is connected to the
+    /// process, otherwise it is relayed by the parent.  The parent
+    /// must run a `Dispatcher` with a relay policy that allows the
+    /// route, and the receiver gets it via its own `Dispatcher`.
+    /// Relayed payloads are limited to `Message::MAX_PAYLOAD_LENGTH`.
+    pub async fn send_to<T: Serialize>(
+        &self,
+        id: usize,
+        message: Message,
+        fd: Option<&Fd>,
+        data: &T,
+    ) -> Result<(), Error> {
+        match self.peers.get(id).and_then(|peer| peer.handler.as_ref()) {
+            Some(handler) => handler.send_message(message, fd, data).await,
+            None => {
+                let parent = &self.peers[0];
+                let data = parent.encode(data)?;
+                parent.send_relay(id, message, fd.as_slice(), &data).await
+            }
+        }
+        .map_err(Into::into)
+    }
+
+    /// Send a message to the parent and all connected siblings.
+    ///
+    /// See `Parent::multicast` for the fd and error handling.
+    pub async fn broadcast<T: Serialize>(
+        &self,
+        message: Message,
+        fd: Option<&Fd>,
+        data: &T,
+    ) -> Result<(), Error> {
+        multicast(&self.peers, None, |handler| {
+            handler.send_message(message, fd, data)
+        })
+        .await
+    }
+
+    /// Send a message to the peers with the given IDs.
+    ///
+    /// See `Parent::multicast` for the fd and error handling.
+    pub async fn multicast<T: Serialize>(
+        &self,
+        ids: &[usize],
+        message: Message,
+        fd: Option<&Fd>,
+        data: &T,
+    ) -> Result<(), Error> {
+        multicast(&self.peers, Some(ids), |handler| {
+            handler.send_message(message, fd, data)
+        })
+        .await
+    }
+
     /// Forcefully close all imsg handlers without dropping them.
     pub fn shutdown(&self) {
         self.peers
@@ -370,6 +582,152 @@ impl<const N: usize> Child<N> {
     }
 }
 
+/// Identity of a process that is exchanged on every channel.
+#[derive(Debug, serde_derive::Deserialize, serde_derive::Serialize)]
+struct Handshake<'a> {
+    /// The protocol version, this must be the first field.
+    version: u32,
+    /// Process ID.
+    id: u32,
+    /// Process name.
+    name: Cow<'a, str>,
+    /// Build identifier.
+    build_id: Cow<'a, str>,
+}
+
+impl<'a> Handshake<'a> {
+    fn new(id: usize, name: &'a str, build_id: &'a str) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            id: id as u32,
+            name: name.into(),
+            build_id: build_id.into(),
+        }
+    }
+}
+
+/// Exchange the handshake and verify the identity of the remote end.
+///
+/// The handshake fails if the remote end does not answer within the
+/// `Options::handshake_timeout`.
+async fn handshake(
+    handler: &Handler,
+    local: &Handshake<'_>,
+    id: usize,
+    name: &'static str,
+    duration: Duration,
+) -> Result<(), Error> {
+    timeout(duration, handshake_internal(handler, local, id, name))
+        .await
+        .unwrap_or_else(|_| Err(Error::HandshakeFailed(name, "timed out".to_string())))
+}
+
+/// Exchange the handshake without a timeout.
+///
+/// The handshake is always encoded with `bincode`, independent of
+/// the codec of the handler.
+async fn handshake_internal(
+    handler: &Handler,
+    local: &Handshake<'_>,
+    id: usize,
+    name: &'static str,
+) -> Result<(), Error> {
+    let failed = |reason: String| Error::HandshakeFailed(name, reason);
+
+    let data = bincode::serialize(local).map_err(|err| failed(err.to_string()))?;
+    handler
+        .send_data_internal(Message::new(Message::HANDSHAKE), &[], &data)
+        .await?;
+
+    handler.accept_reserved(Message::HANDSHAKE, true);
+    let received = handler.recv_data_internal().await;
+    handler.accept_reserved(Message::HANDSHAKE, false);
+    let data = match received? {
+        Some(received) if received.message.id == Message::HANDSHAKE => received.data,
+        Some(received) => {
+            return Err(failed(format!(
+                "unexpected message {}",
+                received.message.id
+            )))
+        }
+        None => return Err(failed("channel closed".to_string())),
+    };
+
+    // Check the version before the rest of the handshake is decoded.
+    let version = bincode::deserialize::<u32>(&data).map_err(|err| failed(err.to_string()))?;
+    if version != local.version {
+        return Err(failed(format!(
+            "protocol version {}, expected {}",
+            version, local.version
+        )));
+    }
+    let remote =
+        bincode::deserialize::<Handshake<'_>>(&data).map_err(|err| failed(err.to_string()))?;
+    if remote.build_id != local.build_id {
+        return Err(failed(format!(
+            "build {:?}, expected {:?}",
+            remote.build_id, local.build_id
+        )));
+    }
+    if remote.id as usize != id || remote.name != name {
+        return Err(failed(format!(
+            "process {}({}), expected {}({})",
+            remote.name, remote.id, name, id
+        )));
+    }
+
+    Ok(())
+}
+
+/// Send a message to the selected or all connected peers.
+///
+/// `send` is called with the handler of each peer, e.g. with
+/// `send_message` that passes a duplicate of the fd to each of them.
+async fn multicast<'a, F, Fut, const N: usize>(
+    peers: &'a Peers<N>,
+    ids: Option<&[usize]>,
+    send: F,
+) -> Result<(), Error>
+where
+    F: Fn(&'a Handler) -> Fut,
+    Fut: Future<Output = io::Result<()>>,
+{
+    let ids = match ids {
+        Some(ids) => ids.to_vec(),
+        None => peers
+            .iter()
+            .enumerate()
+            .filter_map(|(id, peer)| peer.handler.as_ref().map(|_| id))
+            .collect(),
+    };
+
+    let sends = ids.into_iter().map(|id| {
+        let handler = peers.get(id).and_then(|peer| peer.handler.as_ref());
+        let send = handler.map(&send);
+        async move {
+            let result = match send {
+                Some(send) => send.await,
+                None => Err(io::Error::new(
+                    io::ErrorKind::NotConnected,
+                    "unconnected peer",
+                )),
+            };
+            result.err().map(|err| (id, err))
+        }
+    });
+    let errors = join_all(sends)
+        .await
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>();
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::SendFailed(errors))
+    }
+}
+
 fn set_cloexec(fd: RawFd, add: bool) -> Result<(), Error> {
     let mut flags = FdFlag::from_bits_truncate(fcntl(fd, FcntlArg::F_GETFD)?);
     flags.set(FdFlag::FD_CLOEXEC, add);
@@ -382,6 +740,100 @@ fn path_to_cstr(path: &Path) -> CString {
     unsafe { CString::from_vec_unchecked(ospath) }
 }
 
+/// Return an identifier of the running executable.
+///
+/// The ID is the GNU build ID that the linker stored in the running
+/// executable, or the SHA-256 hash of the executable file if it has
+/// none, so it changes with every build.  It is used as the default
+/// `Options::build_id`; an error to read the file fails the startup
+/// instead of disabling the check.
+pub fn executable_id() -> Result<Cow<'static, str>, Error> {
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    if let Some(build_id) = elf_build_id() {
+        return Ok(hex(&build_id).into());
+    }
+
+    // The link still opens the running file if it was replaced.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    let path = PathBuf::from("/proc/self/exe");
+    #[cfg(not(any(target_os = "android", target_os = "linux")))]
+    let path = env::current_exe()?;
+
+    let mut hasher = Sha256::new();
+    io::copy(&mut fs::File::open(path)?, &mut hasher)?;
+
+    Ok(hex(&hasher.finalize()).into())
+}
+
+/// Return the GNU build ID of the running executable.
+///
+/// The note is read from the loaded program headers, so this does
+/// not access the file.
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn elf_build_id() -> Option<Vec<u8>> {
+    unsafe extern "C" fn callback(
+        info: *mut libc::dl_phdr_info,
+        _size: libc::size_t,
+        data: *mut libc::c_void,
+    ) -> libc::c_int {
+        let info = &*info;
+        let build_id = &mut *(data as *mut Option<Vec<u8>>);
+        let headers = std::slice::from_raw_parts(info.dlpi_phdr, info.dlpi_phnum as usize);
+        *build_id = headers
+            .iter()
+            .filter(|header| header.p_type == libc::PT_NOTE)
+            .find_map(|header| {
+                let notes = std::slice::from_raw_parts(
+                    (info.dlpi_addr as usize + header.p_vaddr as usize) as *const u8,
+                    header.p_memsz as usize,
+                );
+                find_build_id(notes, header.p_align as usize).map(<[u8]>::to_vec)
+            });
+
+        // The first object is the executable.
+        1
+    }
+
+    let mut build_id = None;
+    unsafe {
+        libc::dl_iterate_phdr(Some(callback), &mut build_id as *mut _ as *mut libc::c_void);
+    }
+    build_id
+}
+
+/// Find the descriptor of the `NT_GNU_BUILD_ID` note in the notes.
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn find_build_id(mut notes: &[u8], align: usize) -> Option<&[u8]> {
+    const NT_GNU_BUILD_ID: u32 = 3;
+    let align = |offset: usize| {
+        let align = align.max(4);
+        (offset + align - 1) & !(align - 1)
+    };
+    let word = |data: &[u8], offset: usize| {
+        let bytes = data.get(offset..offset + 4)?;
+        Some(u32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    };
+
+    while !notes.is_empty() {
+        let name_length = word(notes, 0)? as usize;
+        let desc_length = word(notes, 4)? as usize;
+        let name = notes.get(12..12 + name_length)?;
+        let offset = align(12 + name_length);
+        let desc = notes.get(offset..offset + desc_length)?;
+        if word(notes, 8)? == NT_GNU_BUILD_ID && name == b"GNU\0" {
+            return Some(desc);
+        }
+        notes = notes.get(align(offset + desc_length).min(notes.len())..)?;
+    }
+
+    None
+}
+
+/// Format the bytes as a lowercase hex string.
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
 /// Portable wrapper of the daemon(3) function that got removed from macOS.
 pub fn daemon(no_close: bool, no_chdir: bool) -> Result<(), Error> {
     cfg_if::cfg_if! {
@@ -421,3 +873,82 @@ fn new_session(no_close: bool, no_chdir: bool) -> Result<(), Error> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    #[tokio::test]
+    async fn test_handshake() -> Result<(), Error> {
+        let parent = Handshake::new(0, PARENT, "build-1");
+        let child = Handshake::new(1, "child", "build-1");
+        let (left, right) = Handler::pair()?;
+        let (a, b) = tokio::join!(
+            handshake(&left, &parent, 1, "child", TIMEOUT),
+            handshake(&right, &child, 0, PARENT, TIMEOUT),
+        );
+        a?;
+        b?;
+
+        // The channel is still usable after the handshake.
+        left.send_message(Message::min(), None, &()).await?;
+        assert!(right.recv_message::<()>().await?.is_some());
+
+        // A child of a different build.
+        let replaced = Handshake::new(1, "child", "build-2");
+        let (left, right) = Handler::pair()?;
+        let (a, b) = tokio::join!(
+            handshake(&left, &parent, 1, "child", TIMEOUT),
+            handshake(&right, &replaced, 0, PARENT, TIMEOUT),
+        );
+        assert!(matches!(a, Err(Error::HandshakeFailed("child", _))));
+        assert!(matches!(b, Err(Error::HandshakeFailed(PARENT, _))));
+
+        // An unexpected process.
+        let other = Handshake::new(2, "other", "build-1");
+        let (left, right) = Handler::pair()?;
+        let (a, _) = tokio::join!(
+            handshake(&left, &parent, 1, "child", TIMEOUT),
+            handshake(&right, &other, 0, PARENT, TIMEOUT),
+        );
+        match a {
+            Err(err @ Error::HandshakeFailed(..)) => assert_eq!(
+                err.to_string(),
+                "Handshake with child failed: process other(2), expected child(1)"
+            ),
+            result => panic!("unexpected result: {:?}", result),
+        }
+
+        // A different protocol version.
+        let future = Handshake {
+            version: PROTOCOL_VERSION + 1,
+            ..Handshake::new(1, "child", "build-1")
+        };
+        let (left, right) = Handler::pair()?;
+        let (a, _) = tokio::join!(
+            handshake(&left, &parent, 1, "child", TIMEOUT),
+            handshake(&right, &future, 0, PARENT, TIMEOUT),
+        );
+        assert!(matches!(a, Err(Error::HandshakeFailed(_, reason)) if reason.contains("version")));
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_handshake_timeout() -> Result<(), Error> {
+        let child = Handshake::new(1, "child", "build-1");
+        let (left, _right) = Handler::pair()?;
+
+        // The parent never answers.
+        match handshake(&left, &child, 0, PARENT, TIMEOUT).await {
+            Err(err @ Error::HandshakeFailed(..)) => {
+                assert_eq!(err.to_string(), "Handshake with parent failed: timed out")
+            }
+            result => panic!("unexpected result: {:?}", result),
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,56 @@
+//! Control requests from the parent to the children.
+//!
+//! The parent sends them with `Message::CONTROL`.  A child receives
+//! them with a `Dispatcher`, which accepts this reserved ID on the
+//! channel to the parent only: it reports them as `Event::Control`,
+//! changes the log filter automatically and stops after a
+//! `Control::Shutdown`.  Other children reject the requests like any
+//! other reserved ID.  Keepalive requests are answered by every
+//! handler, see `Handler::ping`.
+
+use crate::{
+    error::Error,
+    imsg::Message,
+    process::{multicast, Parent},
+};
+use serde_derive::{Deserialize, Serialize};
+
+/// Standard control request.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[non_exhaustive]
+pub enum Control {
+    /// Finish the pending work and exit.
+    Shutdown,
+    /// Reload the configuration.
+    Reload,
+    /// Change the log filter, e.g. `debug` or `privsep=trace`.
+    LogLevel(String),
+}
+
+impl Control {
+    /// Apply the request to the process, if it is handled
+    /// automatically.
+    ///
+    /// This changes the log filter with the `log` feature.
+    pub fn apply(&self) {
+        #[cfg(feature = "log")]
+        if let Self::LogLevel(filter) = self {
+            privsep_log::set_filter(Some(filter));
+        }
+    }
+}
+
+impl<const N: usize> Parent<N> {
+    /// Send a control request to the children, or to all connected
+    /// children if `ids` is `None`.
+    ///
+    /// See `Parent::multicast` for the error handling.
+    pub async fn control(&self, ids: Option<&[usize]>, control: &Control) -> Result<(), Error> {
+        // `Message::CONTROL` is reserved and only sent internally.
+        let message = Message::new(Message::CONTROL);
+        multicast(&self.children, ids, |handler| {
+            handler.send_message_internal(message, &[], control)
+        })
+        .await
+    }
+}
@@ -0,0 +1,122 @@
+//! Keepalive and watchdog of the channels between the processes.
+//!
+//! The watchdog sends a `Message::PING` to each watched peer in every
+//! interval.  The peer's handler answers while the peer receives
+//! messages, so a peer that hangs or deadlocks stops answering even if
+//! its socket is still open.  The channels between the parent and its
+//! children accept the requests, see `Handler::accept_reserved`.
+
+use crate::{
+    error::Error,
+    process::{Child, Parent, Peer},
+};
+use nix::sys::signal::{kill, Signal};
+use std::{collections::HashSet, io, time::Duration};
+use tokio::time::{interval, MissedTickBehavior};
+
+/// What the watchdog does when a peer stopped answering.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WatchdogAction {
+    /// Log the unresponsive peer with the `log` feature and continue.
+    Log,
+    /// Log and kill the unresponsive child.
+    ///
+    /// A child cannot kill its parent, it terminates like with
+    /// `Terminate` instead.
+    Kill,
+    /// Log and return `Error::Terminated` from the watchdog.
+    Terminate,
+}
+
+/// Keepalive options of the watchdog.
+#[derive(Clone, Copy, Debug)]
+pub struct Keepalive {
+    /// Interval between the keepalive requests, must not be zero.
+    pub interval: Duration,
+    /// Number of intervals without a message until a peer is
+    /// unresponsive, must not be zero.
+    pub misses: u32,
+    /// What happens when a peer stopped answering.
+    pub action: WatchdogAction,
+}
+
+impl Default for Keepalive {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(10),
+            misses: 3,
+            action: WatchdogAction::Log,
+        }
+    }
+}
+
+impl<const N: usize> Parent<N> {
+    /// Watch the connected children.
+    ///
+    /// This runs until the action is `WatchdogAction::Terminate` and a
+    /// child stopped answering.  Both ends must keep receiving
+    /// messages, e.g. in a `Dispatcher`, to answer the requests and to
+    /// see the answers.  A zero interval or number of misses is
+    /// rejected with `io::ErrorKind::InvalidInput`.
+    pub async fn watchdog(&self, keepalive: &Keepalive) -> Result<(), Error> {
+        watch(self.children.iter().enumerate(), keepalive, true).await
+    }
+}
+
+impl<const N: usize> Child<N> {
+    /// Watch the parent.
+    ///
+    /// See `Parent::watchdog`.
+    pub async fn watchdog(&self, keepalive: &Keepalive) -> Result<(), Error> {
+        watch(self.peers.iter().enumerate().take(1), keepalive, false).await
+    }
+}
+
+async fn watch<'a>(
+    peers: impl Iterator<Item = (usize, &'a Peer)> + Clone,
+    keepalive: &Keepalive,
+    is_parent: bool,
+) -> Result<(), Error> {
+    if keepalive.interval.is_zero() || keepalive.misses == 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid keepalive").into());
+    }
+    let timeout = keepalive.interval * keepalive.misses;
+    let mut interval = interval(keepalive.interval);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut unresponsive = HashSet::new();
+
+    loop {
+        interval.tick().await;
+
+        for (id, peer) in peers.clone() {
+            let handler = match peer.handler {
+                Some(ref handler) => handler,
+                None => continue,
+            };
+            if handler.idle() < timeout {
+                unresponsive.remove(&id);
+                // Retry a request that could not be written yet, but
+                // don't queue more requests if the peer is not reading.
+                let _ = handler.try_flush();
+                if handler.queued() == 0 {
+                    let _ = handler.ping();
+                }
+                continue;
+            }
+
+            // Act only once until the peer answers again.
+            if !unresponsive.insert(id) {
+                continue;
+            }
+            #[cfg(feature = "log")]
+            privsep_log::warn!("{} stopped answering for {:?}", peer.name, handler.idle());
+            match keepalive.action {
+                WatchdogAction::Log => (),
+                WatchdogAction::Kill if is_parent => {
+                    let _ = kill(peer.pid, Signal::SIGKILL);
+                }
+                _ => return Err(Error::Terminated(peer.name)),
+            }
+        }
+    }
+}
@@ -0,0 +1,28 @@
+// Not every test crate uses all fixtures.
+#![allow(dead_code)]
+
+use privsep::{
+    imsg::Handler,
+    process::{Parent, Peer, Peers},
+};
+
+/// Create the peers with their names and channels.
+pub fn peers<const N: usize>(peers: [(&'static str, Option<Handler>); N]) -> Peers<N> {
+    let mut result = Peers::<N>::new();
+    for (name, handler) in peers {
+        result.push(Peer {
+            name,
+            handler,
+            ..Default::default()
+        });
+    }
+    result
+}
+
+/// Create a parent with the channels to its children.
+pub fn parent<const N: usize>(children: [(&'static str, Option<Handler>); N]) -> Parent<N> {
+    Parent {
+        pid: nix::unistd::getpid(),
+        children: peers(children),
+    }
+}
@@ -0,0 +1,411 @@
+use nix::unistd::Pid;
+use parking_lot::Mutex;
+use privsep::{
+    imsg::{self, Dispatcher, Event},
+    net::Fd,
+    process::{Child, Control},
+    Error,
+};
+use std::{net::TcpListener, os::unix::io::IntoRawFd, sync::Arc};
+
+mod common;
+
+const HELLO: u32 = 10;
+const COUNT: u32 = 11;
+const OTHER: u32 = 12;
+
+#[tokio::test]
+async fn test_dispatch() -> Result<(), Error> {
+    let (parent, remote_parent) = imsg::Handler::pair()?;
+    let (sibling, remote_sibling) = imsg::Handler::pair()?;
+
+    let peers = common::peers([
+        ("parent", Some(parent)),
+        ("unconnected", None),
+        ("sibling", Some(sibling)),
+    ]);
+
+    let received = Arc::new(Mutex::new(vec![]));
+    let events = Arc::new(Mutex::new(vec![]));
+
+    let mut dispatcher = Dispatcher::new();
+    dispatcher
+        .register(0, HELLO, {
+            let received = received.clone();
+            move |incoming: imsg::Incoming<String>| {
+                received
+                    .lock()
+                    .push(format!("{} {}", incoming.peer, incoming.data));
+                async { Ok(()) }
+            }
+        })
+        .register_any(COUNT, {
+            let received = received.clone();
+            move |incoming: imsg::Incoming<u32>| {
+                received
+                    .lock()
+                    .push(format!("{} count {}", incoming.peer, incoming.data));
+                async { Ok(()) }
+            }
+        })
+        .on_event({
+            let events = events.clone();
+            move |event| {
+                events.lock().push(event);
+                async { Ok(()) }
+            }
+        });
+
+    tokio::spawn(async move {
+        remote_parent
+            .send_message(HELLO.into(), None, &"hello")
+            .await?;
+        remote_parent
+            .send_message(COUNT.into(), None, &1u32)
+            .await?;
+        remote_sibling
+            .send_message(COUNT.into(), None, &2u32)
+            .await?;
+        // Not registered for the sibling.
+        remote_sibling
+            .send_message(HELLO.into(), None, &"hello")
+            .await?;
+        remote_sibling.send_message(OTHER.into(), None, &()).await?;
+        Ok::<_, std::io::Error>(())
+    });
+
+    // Returns after both remote ends are dropped.
+    dispatcher.run(&peers).await?;
+
+    let mut received = received.lock().clone();
+    received.sort();
+    assert_eq!(received, ["0 count 1", "0 hello", "2 count 2"]);
+
+    let events = std::mem::take(&mut *events.lock());
+    assert_eq!(events.len(), 4);
+    for peer in [0, 2] {
+        assert!(events
+            .iter()
+            .any(|event| matches!(event, Event::Disconnected(id) if *id == peer)));
+    }
+    let unhandled = events
+        .iter()
+        .filter_map(|event| match event {
+            Event::Unhandled(2, message) => Some(message.id),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(unhandled, [HELLO, OTHER]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_dispatch_error() -> Result<(), Error> {
+    let (handler, remote) = imsg::Handler::pair()?;
+    let peers = common::peers([("parent", Some(handler))]);
+
+    let mut dispatcher = Dispatcher::new();
+    dispatcher.register(0, HELLO, |_: imsg::Incoming<()>| async {
+        Err(Error::Terminated("hello"))
+    });
+
+    remote.send_message(HELLO.into(), None, &()).await?;
+    match dispatcher.run(&peers).await {
+        Err(Error::Terminated(name)) => assert_eq!(name, "hello"),
+        result => panic!("unexpected result: {:?}", result),
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_dispatch_relay() -> Result<(), Error> {
+    let (parent_a, a) = imsg::Handler::pair()?;
+    let (parent_b, b) = imsg::Handler::pair()?;
+    let b = b.with_options(imsg::Options {
+        credentials: true,
+        ..Default::default()
+    });
+
+    let parent = common::peers([
+        ("parent", None),
+        ("a", Some(parent_a)),
+        ("b", Some(parent_b)),
+    ]);
+    let a = Child {
+        name: "a",
+        pid: Pid::this(),
+        peers: common::peers([("parent", Some(a)), ("a", None), ("b", None)]),
+    };
+    let b = Child {
+        name: "b",
+        pid: Pid::this(),
+        peers: common::peers([("parent", Some(b)), ("a", None), ("b", None)]),
+    };
+
+    // Only allow messages from a to b.
+    let events = Arc::new(Mutex::new(vec![]));
+    let mut relay = Dispatcher::new();
+    relay.relay(|from, to, _| (from, to) == (1, 2)).on_event({
+        let events = events.clone();
+        move |event| {
+            events.lock().push(event);
+            async { Ok(()) }
+        }
+    });
+
+    let received = Arc::new(Mutex::new(vec![]));
+    let mut dispatcher = Dispatcher::new();
+    dispatcher.register(1, HELLO, {
+        let received = received.clone();
+        move |incoming: imsg::Incoming<String>| {
+            // The parent sets the PID of a and removes its own credentials.
+            assert_eq!(incoming.message.pid, Pid::parent().as_raw());
+            assert!(incoming.credentials.is_none());
+            received
+                .lock()
+                .push((incoming.peer, incoming.fds.len(), incoming.data));
+            async { Err(Error::Terminated("a")) }
+        }
+    });
+
+    let fd = Fd::from(TcpListener::bind("127.0.0.1:0")?.into_raw_fd());
+    let (parent_result, a_result, b_result) = tokio::join!(
+        relay.run(&parent),
+        async move { a.send_to(2, HELLO.into(), Some(&fd), &"hello").await },
+        async move {
+            b.send_to(1, HELLO.into(), None, &"denied").await?;
+            dispatcher.run(&b).await
+        },
+    );
+    parent_result?;
+    a_result?;
+    assert!(matches!(b_result, Err(Error::Terminated("a"))));
+
+    assert_eq!(*received.lock(), [(1, 1, "hello".to_string())]);
+    let denied = events
+        .lock()
+        .iter()
+        .filter_map(|event| match event {
+            Event::RelayDenied(from, to, message) => Some((*from, *to, message.id)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(denied, [(2, 1, HELLO)]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_dispatch_relay_blocked() -> Result<(), Error> {
+    let (parent_a, a) = imsg::Handler::pair()?;
+    let (parent_b, _b) = imsg::Handler::pair()?;
+    let (parent_c, c) = imsg::Handler::pair()?;
+
+    let parent = common::peers([
+        ("parent", None),
+        ("a", Some(parent_a)),
+        ("b", Some(parent_b)),
+        ("c", Some(parent_c)),
+    ]);
+    let a = Child {
+        name: "a",
+        pid: Pid::this(),
+        peers: common::peers([("parent", Some(a)), ("a", None), ("b", None), ("c", None)]),
+    };
+
+    let events = Arc::new(Mutex::new(vec![]));
+    let mut relay = Dispatcher::new();
+    relay
+        .relay(|_, _, _| true)
+        .register(1, HELLO, |_: imsg::Incoming<()>| async {
+            Err(Error::Terminated("a"))
+        })
+        .on_event({
+            let events = events.clone();
+            move |event| {
+                events.lock().push(event);
+                async { Ok(()) }
+            }
+        });
+
+    // b never reads, the messages to it fill its socket buffer.
+    let data = vec![0u8; 16 * 1024];
+    let (parent_result, a_result) = tokio::join!(relay.run(&parent), async move {
+        // Relayed messages are not fragmented.
+        let large = vec![0u8; imsg::Message::MAX_PAYLOAD_LENGTH];
+        match a.send_to(2, COUNT.into(), None, &large).await {
+            Err(Error::IoError(err)) => assert_eq!(err.kind(), std::io::ErrorKind::InvalidData),
+            result => panic!("unexpected result: {:?}", result),
+        }
+        for _ in 0..64 {
+            a.send_to(2, COUNT.into(), None, &data).await?;
+        }
+        a.send_to(3, COUNT.into(), None, &1u32).await?;
+        a.send_to(0, HELLO.into(), None, &()).await
+    });
+    assert!(matches!(parent_result, Err(Error::Terminated("a"))));
+    a_result?;
+
+    // The traffic of a to c and to the parent was not blocked.
+    let c = common::peers([("parent", Some(c)), ("a", None), ("b", None), ("c", None)]);
+    let mut dispatcher = Dispatcher::new();
+    dispatcher.register(1, COUNT, |incoming: imsg::Incoming<u32>| async move {
+        assert_eq!(incoming.data, 1);
+        Err(Error::Terminated("c"))
+    });
+    assert!(matches!(
+        dispatcher.run(&c).await,
+        Err(Error::Terminated("c"))
+    ));
+    assert!(events
+        .lock()
+        .iter()
+        .any(|event| matches!(event, Event::RelayDenied(1, 2, _))));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_dispatch_invalid() -> Result<(), Error> {
+    use std::{io::Write, os::unix::net::UnixStream};
+    use zerocopy::AsBytes;
+
+    // The malicious child writes raw frames to the parent.
+    let (parent_a, mut a) = UnixStream::pair()?;
+    parent_a.set_nonblocking(true)?;
+    let parent_a = imsg::Handler::from_raw_fd(parent_a)?;
+    let (parent_b, b) = imsg::Handler::pair()?;
+
+    let parent = common::peers([
+        ("parent", None),
+        ("a", Some(parent_a)),
+        ("b", Some(parent_b)),
+    ]);
+
+    let received = Arc::new(Mutex::new(vec![]));
+    let events = Arc::new(Mutex::new(vec![]));
+    let mut relay = Dispatcher::new();
+    relay
+        .relay(|_, _, _| true)
+        .register_any(HELLO, {
+            let received = received.clone();
+            move |incoming: imsg::Incoming<String>| {
+                received.lock().push((incoming.peer, incoming.data));
+                async { Ok(()) }
+            }
+        })
+        .on_event({
+            let events = events.clone();
+            move |event| {
+                events.lock().push(event);
+                async { Ok(()) }
+            }
+        });
+
+    let frame = |message: imsg::Message, data: &[u8]| {
+        let message = imsg::Message {
+            length: (imsg::Message::HEADER_LENGTH + data.len()) as u16,
+            ..message
+        };
+        [message.as_bytes(), data].concat()
+    };
+    // A reserved message is rejected before the policy runs.
+    let connect = imsg::Message {
+        length: imsg::Message::HEADER_LENGTH as u16,
+        ..imsg::Message::connect(2)
+    };
+    let envelope = imsg::Message {
+        peer_id: 2,
+        ..imsg::Message::new(imsg::Message::RELAY)
+    };
+    a.write_all(&frame(envelope, connect.as_bytes()))?;
+    // The payload cannot be decoded.
+    a.write_all(&frame(HELLO.into(), &[0xff; 3]))?;
+    // The invalid header disconnects the peer.
+    a.write_all(
+        imsg::Message {
+            length: 4,
+            ..imsg::Message::min()
+        }
+        .as_bytes(),
+    )?;
+
+    // The honest peer is still served.
+    b.send_message(HELLO.into(), None, &"hello").await?;
+    drop(b);
+    relay.run(&parent).await?;
+
+    assert_eq!(*received.lock(), [(2, "hello".to_string())]);
+    let events = events.lock();
+    let invalid = events
+        .iter()
+        .filter_map(|event| match event {
+            Event::InvalidMessage(1, err) => Some(err.kind()),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(invalid, [std::io::ErrorKind::InvalidData; 3]);
+    for peer in [1, 2] {
+        assert!(events
+            .iter()
+            .any(|event| matches!(event, Event::Disconnected(id) if *id == peer)));
+    }
+
+    let metrics = parent[1].metrics().expect("metrics");
+    assert_eq!(metrics.protocol_errors, 2);
+    assert_eq!(metrics.decode_errors, 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_dispatch_control() -> Result<(), Error> {
+    let (child, remote_child) = imsg::Handler::pair()?;
+    let (sibling, _remote_sibling) = imsg::Handler::pair()?;
+
+    let parent = common::parent([("parent", None), ("child", Some(child))]);
+    let peers = common::peers([("parent", Some(remote_child)), ("sibling", Some(sibling))]);
+
+    let events = Arc::new(Mutex::new(vec![]));
+    let mut dispatcher = Dispatcher::new();
+    dispatcher.on_event({
+        let events = events.clone();
+        move |event| {
+            events.lock().push(event);
+            async { Ok(()) }
+        }
+    });
+
+    for control in [
+        Control::LogLevel("debug".to_string()),
+        Control::Reload,
+        Control::Shutdown,
+    ] {
+        parent.control(None, &control).await?;
+    }
+
+    // The dispatcher stops after the shutdown request, although the
+    // sibling is still connected.
+    dispatcher.run(&peers).await?;
+
+    let events = events.lock();
+    let controls = events
+        .iter()
+        .map(|event| match event {
+            Event::Control(control) => control.clone(),
+            event => panic!("unexpected event: {:?}", event),
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        controls,
+        [
+            Control::LogLevel("debug".to_string()),
+            Control::Reload,
+            Control::Shutdown
+        ]
+    );
+
+    Ok(())
+}
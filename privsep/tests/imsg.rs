@@ -1,7 +1,20 @@
-use privsep::{imsg, net::Fd};
+use privsep::{
+    imsg,
+    net::{Fd, FdKind},
+    Error,
+};
+use privsep_derive::Imsg;
 use serde_derive::{Deserialize, Serialize};
-use std::{io, net::TcpListener, os::unix::io::IntoRawFd, time::Duration};
+use std::{
+    fs::File,
+    io,
+    net::{TcpListener, UdpSocket},
+    os::unix::io::{AsRawFd, FromRawFd, IntoRawFd},
+    sync::Arc,
+    time::Duration,
+};
 use tokio::time::interval;
+use zerocopy::{AsBytes, FromBytes};
 
 #[derive(Debug, Serialize, Deserialize)]
 struct Message {
@@ -44,7 +57,7 @@ async fn unix_channel() -> Result<(), std::io::Error> {
     });
 
     let res = loop {
-        match { receiver.recv_message::<Message>().await } {
+        match receiver.recv_message::<Message>().await {
             Ok(None) => break Ok(()),
             Ok(Some((imsg, fd, message))) => {
                 count -= 1;
@@ -64,3 +77,1432 @@ async fn unix_channel() -> Result<(), std::io::Error> {
 
     res
 }
+
+#[tokio::test]
+async fn test_imsg_fds() -> Result<(), io::Error> {
+    let (sender, receiver) = imsg::Handler::pair()?;
+
+    let listeners = (0..3)
+        .map(|_| TcpListener::bind("127.0.0.1:0"))
+        .collect::<Result<Vec<_>, _>>()?;
+    let addrs = listeners
+        .iter()
+        .map(TcpListener::local_addr)
+        .collect::<Result<Vec<_>, _>>()?;
+    let fds = listeners
+        .into_iter()
+        .map(|listener| Fd::from(listener.into_raw_fd()))
+        .collect::<Vec<_>>();
+
+    sender
+        .send_message_with_fds(
+            imsg::Message::min(),
+            &fds.iter().collect::<Vec<_>>(),
+            &"fds".to_string(),
+        )
+        .await?;
+
+    let (_, received, data) = receiver
+        .recv_message_with_fds::<String>()
+        .await?
+        .expect("message");
+    assert_eq!(data, "fds");
+    assert_eq!(received.len(), 3);
+
+    for (fd, addr) in received.into_iter().zip(addrs) {
+        let listener = unsafe { TcpListener::from_raw_fd(fd.into_raw_fd()) };
+        assert_eq!(listener.local_addr()?, addr);
+    }
+
+    // Sending more than the configured maximum must fail.
+    let sender = sender.with_options(imsg::Options {
+        max_fds: 2,
+        ..Default::default()
+    });
+    let result = sender
+        .send_message_with_fds(imsg::Message::min(), &fds.iter().collect::<Vec<_>>(), &())
+        .await;
+    assert_eq!(
+        result.map_err(|err| err.kind()),
+        Err(io::ErrorKind::InvalidInput)
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_imsg_fd_queue() -> Result<(), io::Error> {
+    let (sender, receiver) = imsg::Handler::pair()?;
+
+    // Send a burst of messages where only some of them carry fds.
+    let mut expected = vec![];
+    for id in 0..8usize {
+        let listeners = (0..id % 3)
+            .map(|_| TcpListener::bind("127.0.0.1:0"))
+            .collect::<Result<Vec<_>, _>>()?;
+        expected.push(
+            listeners
+                .iter()
+                .map(TcpListener::local_addr)
+                .collect::<Result<Vec<_>, _>>()?,
+        );
+        let fds = listeners
+            .into_iter()
+            .map(|listener| Fd::from(listener.into_raw_fd()))
+            .collect::<Vec<_>>();
+        sender
+            .send_message_with_fds(imsg::Message::min(), &fds.iter().collect::<Vec<_>>(), &id)
+            .await?;
+    }
+    drop(sender);
+
+    while let Some((message, fds, id)) = receiver.recv_message_with_fds::<usize>().await? {
+        assert_eq!(message.fds(), fds.len());
+        let addrs = fds
+            .into_iter()
+            .map(|fd| unsafe { TcpListener::from_raw_fd(fd.into_raw_fd()) }.local_addr())
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(addrs, expected[id], "message {}", id);
+    }
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_imsg_fragments() -> Result<(), io::Error> {
+    let (sender, receiver) = imsg::Handler::pair()?;
+    let receiver = receiver.with_options(imsg::Options {
+        max_length: 512 * 1024,
+        ..Default::default()
+    });
+
+    let large = (0..300_000u32).map(|i| i as u8).collect::<Vec<u8>>();
+    let too_large = vec![0u8; 600_000];
+    let fd = Fd::from(TcpListener::bind("127.0.0.1:0")?.into_raw_fd());
+
+    let task = tokio::spawn(async move {
+        sender
+            .send_message(imsg::Message::min(), Some(&fd), &large)
+            .await?;
+        sender
+            .send_message(imsg::Message::min(), None, &"small".to_string())
+            .await?;
+        sender
+            .send_message(imsg::Message::min(), None, &too_large)
+            .await
+    });
+
+    let (message, fd, data) = receiver.recv_message::<Vec<u8>>().await?.expect("message");
+    assert_eq!(message.flags & imsg::Message::FLAG_MORE, 0);
+    assert!(fd.is_some());
+    assert_eq!(data.len(), 300_000);
+    assert!(data.iter().enumerate().all(|(i, b)| *b == i as u8));
+
+    let (_, fd, data) = receiver.recv_message::<String>().await?.expect("message");
+    assert!(fd.is_none());
+    assert_eq!(data, "small");
+
+    let err = receiver
+        .recv_message::<Vec<u8>>()
+        .await
+        .expect_err("message too long");
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+    drop(receiver);
+    let _ = task.await;
+
+    Ok(())
+}
+
+#[derive(Debug, PartialEq, Imsg)]
+enum Protocol {
+    Hello(String),
+    Stats {
+        count: u64,
+        name: String,
+    },
+    #[id = 100]
+    Ping,
+    Pair(u32, u32),
+}
+
+#[tokio::test]
+async fn test_imsg_typed() -> Result<(), io::Error> {
+    let (sender, receiver) = imsg::Handler::pair()?;
+
+    assert_eq!(Protocol::HELLO_ID, imsg::Message::RESERVED);
+    assert_eq!(Protocol::PAIR_ID, 101);
+
+    let messages = vec![
+        Protocol::Hello("hello".to_string()),
+        Protocol::Stats {
+            count: 23,
+            name: "stats".to_string(),
+        },
+        Protocol::Ping,
+        Protocol::Pair(1, 2),
+    ];
+    for message in &messages {
+        sender.send_imsg(message, None).await?;
+    }
+    for expected in messages {
+        let (message, _, data) = receiver.recv_imsg::<Protocol>().await?.expect("message");
+        assert_eq!(message.id, imsg::Imsg::id(&expected));
+        assert_eq!(data, expected);
+    }
+
+    // Unknown message ID.
+    sender.send_message(99u32.into(), None, &()).await?;
+    let err = receiver.recv_imsg::<Protocol>().await.expect_err("unknown");
+    assert!(matches!(
+        err.get_ref().and_then(|err| err.downcast_ref()),
+        Some(imsg::ProtocolError::UnknownId(99))
+    ));
+
+    // Payload does not match the variant.
+    sender
+        .send_message(Protocol::PAIR_ID.into(), None, &1u8)
+        .await?;
+    let err = receiver.recv_imsg::<Protocol>().await.expect_err("payload");
+    assert!(matches!(
+        err.get_ref().and_then(|err| err.downcast_ref()),
+        Some(imsg::ProtocolError::InvalidPayload(101, _))
+    ));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_imsg_codecs() -> Result<(), io::Error> {
+    #[allow(unused_mut)]
+    let mut codecs: Vec<Arc<dyn imsg::Codec>> = vec![
+        Arc::new(imsg::Bincode::default()),
+        Arc::new(imsg::Bincode { limit: Some(64) }),
+    ];
+    #[cfg(feature = "postcard")]
+    codecs.push(Arc::new(imsg::codec::Postcard));
+    #[cfg(feature = "cbor")]
+    codecs.push(Arc::new(imsg::codec::Cbor));
+
+    for codec in codecs {
+        let options = imsg::Options {
+            codec,
+            ..Default::default()
+        };
+        let (sender, receiver) = imsg::Handler::pair()?;
+        let sender = sender.with_options(options.clone());
+        let receiver = receiver.with_options(options);
+
+        let message = Message {
+            id: 1,
+            name: "codec".to_string(),
+        };
+        sender
+            .send_message(imsg::Message::min(), None, &message)
+            .await?;
+        let (_, _, received) = receiver.recv_message::<Message>().await?.expect("message");
+        assert_eq!(received.id, message.id);
+        assert_eq!(received.name, message.name);
+
+        sender.send_imsg(&Protocol::Pair(1, 2), None).await?;
+        let (_, _, received) = receiver.recv_imsg::<Protocol>().await?.expect("message");
+        assert_eq!(received, Protocol::Pair(1, 2));
+    }
+
+    // The size limit is enforced by the codec.
+    let (sender, _) = imsg::Handler::pair()?;
+    let sender = sender.with_options(imsg::Options {
+        codec: Arc::new(imsg::Bincode { limit: Some(4) }),
+        ..Default::default()
+    });
+    assert!(sender
+        .send_message(imsg::Message::min(), None, &"too long")
+        .await
+        .is_err());
+
+    Ok(())
+}
+
+#[derive(Debug, Default, PartialEq, AsBytes, FromBytes)]
+#[repr(C)]
+struct Stats {
+    count: u64,
+    errors: u32,
+    flags: u32,
+}
+
+#[tokio::test]
+async fn test_imsg_raw() -> Result<(), io::Error> {
+    let (sender, receiver) = imsg::Handler::pair()?;
+
+    let stats = Stats {
+        count: 23,
+        errors: 1,
+        flags: 0xff,
+    };
+    sender.send_raw(imsg::Message::min(), None, &stats).await?;
+    let (message, _, received) = receiver.recv_raw::<Stats>().await?.expect("message");
+    assert_eq!(
+        message.length as usize,
+        imsg::Message::HEADER_LENGTH + std::mem::size_of::<Stats>()
+    );
+    assert_eq!(received, stats);
+
+    // The payload length must match the struct.
+    sender
+        .send_raw(imsg::Message::min(), None, &[0u8; 3])
+        .await?;
+    let err = receiver.recv_raw::<Stats>().await.expect_err("length");
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+    Ok(())
+}
+
+/// Build a message as it is encoded by OpenBSD's `imsg_compose`.
+fn openbsd_imsg(id: u32, hasfd: bool, peer_id: u32, pid: i32, data: &[u8]) -> Vec<u8> {
+    let length = (imsg::Message::HEADER_LENGTH + data.len()) as u16;
+    let flags = u16::from(hasfd);
+    let mut buf = Vec::new();
+    buf.extend_from_slice(&id.to_ne_bytes());
+    buf.extend_from_slice(&length.to_ne_bytes());
+    buf.extend_from_slice(&flags.to_ne_bytes());
+    buf.extend_from_slice(&peer_id.to_ne_bytes());
+    buf.extend_from_slice(&pid.to_ne_bytes());
+    buf.extend_from_slice(data);
+    buf
+}
+
+#[tokio::test]
+async fn test_imsg_openbsd() -> Result<(), io::Error> {
+    use privsep::net::{SocketAncillary, StdUnixStreamExt};
+    use std::{
+        io::{IoSlice, Read, Write},
+        os::unix::{io::AsRawFd, net::UnixStream},
+    };
+
+    let (left, right) = UnixStream::pair()?;
+    left.set_nonblocking(true)?;
+    let handler = imsg::Handler::from_raw_fd(left)?.with_options(imsg::Options {
+        wire_format: imsg::WireFormat::OpenBsd,
+        ..Default::default()
+    });
+    let pid = std::process::id() as i32;
+
+    // Reserved IDs are allowed, the encoding matches imsg_compose.
+    let stats = Stats {
+        count: 23,
+        errors: 1,
+        flags: 0xff,
+    };
+    let message = imsg::Message {
+        peer_id: 7,
+        ..imsg::Message::new(1u32)
+    };
+    handler.send_raw(message, None, &stats).await?;
+    let expected = openbsd_imsg(1, false, 7, pid, stats.as_bytes());
+    let mut buf = vec![0u8; expected.len()];
+    (&right).read_exact(&mut buf)?;
+    assert_eq!(buf, expected);
+
+    // Only one fd and no fragments.
+    let fd1 = Fd::from(TcpListener::bind("127.0.0.1:0")?.into_raw_fd());
+    let fd2 = Fd::from(TcpListener::bind("127.0.0.1:0")?.into_raw_fd());
+    assert!(handler
+        .send_message_with_fds(imsg::Message::new(20u32), &[&fd1, &fd2], &())
+        .await
+        .is_err());
+    assert!(handler
+        .send_raw(
+            imsg::Message::new(20u32),
+            None,
+            &[0u8; imsg::Message::MAX_IMSGSIZE][..]
+        )
+        .await
+        .is_err());
+
+    // Receive a message with an fd from the OpenBSD side.
+    let data = openbsd_imsg(2, true, 3, 1234, stats.as_bytes());
+    let mut ancillary_buffer = [0u8; 128];
+    let mut ancillary = SocketAncillary::new(&mut ancillary_buffer);
+    assert!(ancillary.add_fds(&[fd1.as_raw_fd()]));
+    StdUnixStreamExt::send_vectored_with_ancillary(&right, &[IoSlice::new(&data)], &mut ancillary)?;
+
+    let (message, fd, received) = handler.recv_raw::<Stats>().await?.expect("message");
+    assert_eq!(message.id, 2);
+    assert_eq!(message.peer_id, 3);
+    assert_eq!(message.pid, 1234);
+    assert!(fd.is_some());
+    assert_eq!(received, stats);
+
+    // Messages that are shorter than the header are rejected.
+    let mut data = openbsd_imsg(3, false, 0, 1234, &[]);
+    data[4..6].copy_from_slice(&4u16.to_ne_bytes());
+    (&right).write_all(&data)?;
+    let err = handler.recv_raw::<()>().await.expect_err("length");
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+    Ok(())
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_imsg_send_queue() -> Result<(), io::Error> {
+    let (sender, receiver) = imsg::Handler::pair()?;
+    let high_water_mark = 64 * 1024;
+    let sender = Arc::new(sender.with_options(imsg::Options {
+        high_water_mark,
+        ..Default::default()
+    }));
+    let data = vec![0x55u8; 1024];
+    let count = 2000u32;
+
+    let task = tokio::spawn({
+        let sender = sender.clone();
+        let data = data.clone();
+        async move {
+            let fd = Fd::from(TcpListener::bind("127.0.0.1:0")?.into_raw_fd());
+            for i in 0..count {
+                let message = imsg::Message::new(i + imsg::Message::RESERVED);
+                let fd = if i % 100 == 0 { Some(&fd) } else { None };
+                sender.send_message(message, fd, &data).await?;
+            }
+            Ok::<_, io::Error>(())
+        }
+    });
+
+    // The sender is blocked until the receiver drains the queue.  The
+    // paused clock only advances after all tasks are waiting.
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert!(!task.is_finished());
+    assert!(sender.queued() > 0);
+    assert!(sender.queued() <= high_water_mark + data.len() + imsg::Message::HEADER_LENGTH);
+
+    for i in 0..count {
+        let (message, fd, received) = receiver.recv_message::<Vec<u8>>().await?.expect("message");
+        assert_eq!(message.id, i + imsg::Message::RESERVED);
+        assert_eq!(fd.is_some(), i % 100 == 0, "fd of message {}", i);
+        assert_eq!(received, data);
+    }
+    task.await.expect("task")?;
+
+    // Flush waits until the queue is empty.
+    sender.send_message(imsg::Message::min(), None, &()).await?;
+    sender.flush().await?;
+    assert_eq!(sender.queued(), 0);
+    let (message, _, _) = receiver.recv_message::<()>().await?.expect("message");
+    assert_eq!(message.id, imsg::Message::RESERVED);
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_imsg_split() -> Result<(), io::Error> {
+    let (left, right) = imsg::Handler::pair()?;
+    let (mut reader, writer) = left.split();
+    let count = 10u32;
+
+    // Many tasks send with clones of the writer.
+    let tasks = (0..count)
+        .map(|i| {
+            let writer = writer.clone();
+            tokio::spawn(async move { writer.send_message(imsg::Message::min(), None, &i).await })
+        })
+        .collect::<Vec<_>>();
+    for task in tasks {
+        task.await.expect("task")?;
+    }
+
+    // The remote end echoes the messages back to the reader.
+    let mut received = vec![];
+    for _ in 0..count {
+        let (message, _, i) = right.recv_message::<u32>().await?.expect("message");
+        right.send_message(message, None, &i).await?;
+        let (_, _, i) = reader.recv_message::<u32>().await?.expect("message");
+        received.push(i);
+    }
+    received.sort_unstable();
+    assert_eq!(received, (0..count).collect::<Vec<_>>());
+
+    // The halves can only be reunited after all writers are dropped.
+    let other = writer.clone();
+    let (reader, writer) = reader.reunite(writer).expect_err("reunite");
+    drop(other);
+    let handler = reader.reunite(writer).expect("reunite");
+    handler
+        .send_message(imsg::Message::min(), None, &())
+        .await?;
+    assert!(right.recv_message::<()>().await?.is_some());
+
+    Ok(())
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_imsg_cancel() -> Result<(), io::Error> {
+    use privsep::net::{SocketAncillary, StdUnixStreamExt};
+    use std::{
+        io::{IoSlice, Read},
+        os::unix::{io::AsRawFd, net::UnixStream},
+    };
+
+    // Encode a fragmented message with an fd.
+    let data = (0..100_000u32).map(|i| i as u8).collect::<Vec<_>>();
+    let fd = Fd::from(TcpListener::bind("127.0.0.1:0")?.into_raw_fd());
+    let (left, mut right) = UnixStream::pair()?;
+    left.set_nonblocking(true)?;
+    right.set_nonblocking(true)?;
+    let encoder = imsg::Handler::from_raw_fd(left)?;
+    encoder
+        .send_message(imsg::Message::min(), Some(&fd), &data)
+        .await?;
+    let mut encoded = vec![];
+    // The non-blocking read stops with WouldBlock after the message.
+    assert!(right.read_to_end(&mut encoded).is_err());
+    assert!(encoded.len() > data.len());
+
+    let (left, right) = UnixStream::pair()?;
+    left.set_nonblocking(true)?;
+    let receiver = imsg::Handler::from_raw_fd(left)?;
+    let chunks = encoded.chunks(4096).collect::<Vec<_>>();
+    let count = 3;
+
+    // Cancel the receiver after every chunk that doesn't complete the
+    // message.  The paused clock only fires the timer once the
+    // receiver consumed the chunk and waits for more data.
+    let mut cancelled = 0;
+    for _ in 0..count {
+        for (i, chunk) in chunks.iter().enumerate() {
+            let mut ancillary_buffer = [0u8; 128];
+            let mut ancillary = SocketAncillary::new(&mut ancillary_buffer);
+            if i == 0 {
+                assert!(ancillary.add_fds(&[fd.as_raw_fd()]));
+            }
+            StdUnixStreamExt::send_vectored_with_ancillary(
+                &right,
+                &[IoSlice::new(chunk)],
+                &mut ancillary,
+            )?;
+
+            tokio::select! {
+                biased;
+                message = receiver.recv_message::<Vec<u8>>() => {
+                    assert_eq!(i, chunks.len() - 1);
+                    let (message, fd, payload) = message?.expect("message");
+                    assert_eq!(message.id, imsg::Message::RESERVED);
+                    assert!(fd.is_some());
+                    assert_eq!(payload, data);
+                }
+                _ = tokio::time::sleep(Duration::from_millis(1)) => cancelled += 1,
+            }
+        }
+    }
+    assert_eq!(cancelled, count * (chunks.len() - 1));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_imsg_stream_sink() -> Result<(), io::Error> {
+    use futures::{stream, SinkExt, StreamExt, TryStreamExt};
+
+    let (a, remote_a) = imsg::Handler::pair()?;
+    let (b, remote_b) = imsg::Handler::pair()?;
+    let (reader_a, writer_a) = a.split();
+    let (_reader_b, writer_b) = b.split();
+    let (reader_remote_a, writer_remote_a) = remote_a.split();
+    let (reader_remote_b, _writer_remote_b) = remote_b.split();
+
+    // Send with sinks.
+    let mut sink_a = writer_a.into_sink::<u32>();
+    let mut sink_b = writer_b.into_sink::<u32>();
+    let fd = Fd::from(TcpListener::bind("127.0.0.1:0")?.into_raw_fd());
+    sink_a
+        .send((imsg::Message::new(20u32), Some(fd), 0u32))
+        .await?;
+    sink_a
+        .send_all(&mut stream::iter(1..5u32).map(|i| Ok((imsg::Message::new(20u32), None, i))))
+        .await?;
+    sink_b
+        .send_all(&mut stream::iter(5..10u32).map(|i| Ok((imsg::Message::new(30u32), None, i))))
+        .await?;
+    assert!(sink_a
+        .send((imsg::Message::new(1u32), None, 0u32))
+        .await
+        .is_err());
+
+    // Merge the streams of both peers.
+    let merged = stream::select(
+        reader_remote_a.into_stream::<u32>(),
+        reader_remote_b.into_stream::<u32>(),
+    );
+    let mut received = merged.take(10).try_collect::<Vec<_>>().await?;
+    received.sort_by_key(|(_, _, i)| *i);
+    for (i, (message, fd, data)) in received.into_iter().enumerate() {
+        assert_eq!(data, i as u32);
+        assert_eq!(message.id, if i < 5 { 20 } else { 30 });
+        assert_eq!(fd.is_some(), i == 0);
+    }
+
+    // The stream ends after the remote end is closed.
+    let mut stream = reader_a.into_stream::<u32>();
+    drop(writer_remote_a);
+    assert!(tokio::time::timeout(Duration::from_secs(1), stream.next())
+        .await?
+        .is_none());
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_imsg_credentials() -> Result<(), io::Error> {
+    use nix::unistd::{getgid, getuid};
+    use std::{io::Write, os::unix::net::UnixStream};
+
+    let (left, mut right) = UnixStream::pair()?;
+    left.set_nonblocking(true)?;
+    let receiver = imsg::Handler::from_raw_fd(left)?.with_options(imsg::Options {
+        credentials: true,
+        ..Default::default()
+    });
+
+    // The self-reported pid in the header is forged.
+    let message = imsg::Message {
+        pid: 1,
+        ..imsg::Message::min()
+    };
+    right.write_all(message.as_bytes())?;
+
+    let (message, _, (), credentials) = receiver
+        .recv_message_with_credentials()
+        .await?
+        .expect("message");
+    assert_eq!(message.pid, 1);
+    let credentials = credentials.expect("credentials");
+    assert_eq!(credentials.pid, std::process::id() as i32);
+    assert_eq!(credentials.uid, getuid().as_raw());
+    assert_eq!(credentials.gid, getgid().as_raw());
+
+    // Fds are still received with the credentials.
+    let (sender, receiver) = imsg::Handler::pair()?;
+    let receiver = receiver.with_options(imsg::Options {
+        credentials: true,
+        ..Default::default()
+    });
+    let fd = Fd::from(TcpListener::bind("127.0.0.1:0")?.into_raw_fd());
+    sender
+        .send_message(imsg::Message::min(), Some(&fd), &"hello")
+        .await?;
+    let (_, fd, data, credentials) = receiver
+        .recv_message_with_credentials::<String>()
+        .await?
+        .expect("message");
+    assert!(fd.is_some());
+    assert_eq!(data, "hello");
+    assert!(credentials.is_some());
+
+    // Disabled by default.
+    let (sender, receiver) = imsg::Handler::pair()?;
+    sender.send_message(imsg::Message::min(), None, &()).await?;
+    let (_, _, (), credentials) = receiver
+        .recv_message_with_credentials()
+        .await?
+        .expect("message");
+    assert!(credentials.is_none());
+
+    Ok(())
+}
+
+fn protocol_error(err: &io::Error) -> &imsg::ProtocolError {
+    err.get_ref()
+        .and_then(|err| err.downcast_ref())
+        .expect("protocol error")
+}
+
+#[tokio::test]
+async fn test_imsg_invalid_header() -> Result<(), io::Error> {
+    use std::{io::Write, os::unix::net::UnixStream};
+
+    for (length, flags) in [(4, 0), (16, 0x0010), (16, 0x0100), (16, 0x000c)] {
+        let (left, mut right) = UnixStream::pair()?;
+        left.set_nonblocking(true)?;
+        let receiver = imsg::Handler::from_raw_fd(left)?;
+
+        let message = imsg::Message {
+            length,
+            flags,
+            ..imsg::Message::min()
+        };
+        right.write_all(message.as_bytes())?;
+
+        let err = receiver.recv_message::<()>().await.expect_err("header");
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        match protocol_error(&err) {
+            imsg::ProtocolError::InvalidLength(value) => assert_eq!(*value, length),
+            imsg::ProtocolError::InvalidFlags(value) => assert_eq!(*value, flags),
+            err => panic!("unexpected error: {}", err),
+        }
+    }
+
+    // The header claims more fds than allowed.
+    let (left, mut right) = UnixStream::pair()?;
+    left.set_nonblocking(true)?;
+    let receiver = imsg::Handler::from_raw_fd(left)?;
+    let mut message = imsg::Message::min();
+    message.set_fds(imsg::Handler::MAX_FDS + 1)?;
+    right.write_all(message.as_bytes())?;
+    let err = receiver.recv_message::<()>().await.expect_err("fds");
+    assert!(matches!(
+        protocol_error(&err),
+        imsg::ProtocolError::TooManyFds(count) if *count == imsg::Handler::MAX_FDS + 1
+    ));
+
+    // The header claims an fd that was not passed.
+    let (left, mut right) = UnixStream::pair()?;
+    left.set_nonblocking(true)?;
+    let receiver = imsg::Handler::from_raw_fd(left)?;
+    let mut message = imsg::Message::min();
+    message.set_fds(1)?;
+    right.write_all(message.as_bytes())?;
+    let err = receiver.recv_message::<()>().await.expect_err("fds");
+    assert!(matches!(
+        protocol_error(&err),
+        imsg::ProtocolError::MissingFds(1)
+    ));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_imsg_invalid_header_shutdown() -> Result<(), io::Error> {
+    use std::{
+        io::{Read, Write},
+        os::unix::net::UnixStream,
+    };
+
+    for policy in [imsg::LimitPolicy::Error, imsg::LimitPolicy::Disconnect] {
+        let (left, mut right) = UnixStream::pair()?;
+        left.set_nonblocking(true)?;
+        let receiver = imsg::Handler::from_raw_fd(left)?.with_options(imsg::Options {
+            limit_policy: policy,
+            ..Default::default()
+        });
+
+        // A valid message after the bad header is not received.
+        let message = imsg::Message {
+            length: 4,
+            ..imsg::Message::min()
+        };
+        right.write_all(message.as_bytes())?;
+        let message = imsg::Message {
+            length: imsg::Message::HEADER_LENGTH as u16,
+            ..imsg::Message::min()
+        };
+        right.write_all(message.as_bytes())?;
+
+        let err = receiver.recv_message::<()>().await.expect_err("header");
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let err = receiver.recv_message::<()>().await.expect_err("shutdown");
+        assert_eq!(err.kind(), io::ErrorKind::NotConnected);
+
+        // Only the `Disconnect` policy closes the connection.
+        right.set_nonblocking(true)?;
+        let read = right.read(&mut [0u8; 1]);
+        match policy {
+            imsg::LimitPolicy::Disconnect => assert_eq!(read?, 0),
+            _ => assert_eq!(read.expect_err("open").kind(), io::ErrorKind::WouldBlock),
+        }
+
+        // Otherwise messages can still be sent to the peer.
+        let result = receiver.send_message(imsg::Message::min(), None, &()).await;
+        if policy == imsg::LimitPolicy::Error {
+            result?;
+            let mut buf = [0u8; imsg::Message::HEADER_LENGTH];
+            right.read_exact(&mut buf)?;
+            assert_eq!(
+                imsg::Message::read_from(&buf[..]).expect("header").id,
+                imsg::Message::RESERVED
+            );
+        } else {
+            assert!(result.is_err());
+        }
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_imsg_partial_frame_fds() -> Result<(), io::Error> {
+    use privsep::net::{SocketAncillary, StdUnixStreamExt};
+    use std::{io::IoSlice, os::unix::net::UnixStream};
+
+    let (left, right) = UnixStream::pair()?;
+    left.set_nonblocking(true)?;
+    let receiver = imsg::Handler::from_raw_fd(left)?;
+
+    // Trickle a frame without fds and pass an fd with every byte.
+    let message = imsg::Message {
+        length: imsg::Message::HEADER_LENGTH as u16 + 64,
+        ..imsg::Message::min()
+    };
+    let mut data = message.as_bytes().to_vec();
+    data.extend_from_slice(&[0u8; 64]);
+    let fd = Fd::from(TcpListener::bind("127.0.0.1:0")?.into_raw_fd());
+    let task = tokio::spawn(async move {
+        for byte in data.chunks(1) {
+            let mut ancillary_buffer = [0u8; 128];
+            let mut ancillary = SocketAncillary::new(&mut ancillary_buffer);
+            assert!(ancillary.add_fds(&[fd.as_raw_fd()]));
+            if StdUnixStreamExt::send_vectored_with_ancillary(
+                &right,
+                &[IoSlice::new(byte)],
+                &mut ancillary,
+            )
+            .is_err()
+            {
+                break;
+            }
+            tokio::task::yield_now().await;
+        }
+    });
+
+    // The fds are not queued beyond the limit.
+    let err = receiver.recv_message::<()>().await.expect_err("fds");
+    match protocol_error(&err) {
+        imsg::ProtocolError::TooManyFds(count) => {
+            assert!(*count > imsg::Handler::MAX_FDS && *count <= 2 * imsg::Handler::MAX_FDS)
+        }
+        err => panic!("unexpected error: {}", err),
+    }
+    let err = receiver.recv_message::<()>().await.expect_err("shutdown");
+    assert_eq!(err.kind(), io::ErrorKind::NotConnected);
+    task.await.expect("task");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_imsg_reserved() -> Result<(), io::Error> {
+    use std::{io::Write, os::unix::net::UnixStream};
+
+    let (left, mut right) = UnixStream::pair()?;
+    left.set_nonblocking(true)?;
+    let receiver = imsg::Handler::from_raw_fd(left)?;
+
+    let ids = (0..imsg::Message::RESERVED).collect::<Vec<_>>();
+    let mut send = |id| {
+        let message = imsg::Message {
+            length: imsg::Message::HEADER_LENGTH as u16,
+            ..imsg::Message::new(id)
+        };
+        right.write_all(message.as_bytes())
+    };
+    let reserved_id = |err: io::Error| match err.get_ref().and_then(|err| err.downcast_ref()) {
+        Some(imsg::ProtocolError::ReservedId(id)) => Some(*id),
+        _ => None,
+    };
+
+    // The untrusted peer cannot send any reserved ID.
+    for id in ids.iter().copied() {
+        send(id)?;
+        let err = receiver.recv_message::<()>().await.expect_err("reserved");
+        assert_eq!(reserved_id(err), Some(id));
+    }
+    send(imsg::Message::RESERVED)?;
+    let (message, _, ()) = receiver.recv_message().await?.expect("message");
+    assert_eq!(message.id, imsg::Message::RESERVED);
+
+    // Accepting `CONNECT` lets exactly that ID through.
+    receiver.accept_reserved(imsg::Message::CONNECT, true);
+    for id in ids.iter().copied() {
+        send(id)?;
+        let result = receiver.recv_message::<()>().await;
+        if id == imsg::Message::CONNECT {
+            let (message, _, ()) = result?.expect("message");
+            assert_eq!(message.id, imsg::Message::CONNECT);
+        } else {
+            assert_eq!(reserved_id(result.expect_err("reserved")), Some(id));
+        }
+    }
+
+    // And it can be rejected again.
+    receiver.accept_reserved(imsg::Message::CONNECT, false);
+    send(imsg::Message::CONNECT)?;
+    let err = receiver.recv_message::<()>().await.expect_err("reserved");
+    assert_eq!(reserved_id(err), Some(imsg::Message::CONNECT));
+
+    Ok(())
+}
+
+#[tokio::test]
+#[should_panic(expected = "not reserved")]
+async fn test_imsg_accept_unreserved() {
+    let (handler, _remote) = imsg::Handler::pair().expect("pair");
+    handler.accept_reserved(imsg::Message::RESERVED, true);
+}
+
+#[tokio::test]
+async fn test_imsg_interleaved_fragments() -> Result<(), io::Error> {
+    use std::{io::Write, os::unix::net::UnixStream};
+
+    let (left, mut right) = UnixStream::pair()?;
+    left.set_nonblocking(true)?;
+    let receiver = imsg::Handler::from_raw_fd(left)?;
+
+    // The fragments of two messages are interleaved.
+    for (id, flags) in [
+        (imsg::Message::RESERVED, imsg::Message::FLAG_MORE),
+        (imsg::Message::RESERVED + 1, imsg::Message::FLAG_MORE),
+        (imsg::Message::RESERVED, 0),
+        (imsg::Message::RESERVED + 1, 0),
+    ] {
+        let message = imsg::Message {
+            length: imsg::Message::HEADER_LENGTH as u16 + 4,
+            flags,
+            ..imsg::Message::new(id)
+        };
+        right.write_all(message.as_bytes())?;
+        right.write_all(&[0u8; 4])?;
+    }
+
+    let err = receiver.recv_message::<()>().await.expect_err("fragment");
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    let err = receiver.recv_message::<()>().await.expect_err("shutdown");
+    assert_eq!(err.kind(), io::ErrorKind::NotConnected);
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_imsg_limits() -> Result<(), io::Error> {
+    let too_large = vec![0u8; 200_000];
+    let options = imsg::Options {
+        max_length: 100_000,
+        ..Default::default()
+    };
+
+    for policy in [imsg::LimitPolicy::Drop, imsg::LimitPolicy::Error] {
+        let (sender, receiver) = imsg::Handler::pair()?;
+        let receiver = receiver.with_options(imsg::Options {
+            limit_policy: policy,
+            ..options.clone()
+        });
+        let too_large = too_large.clone();
+        let task = tokio::spawn(async move {
+            sender
+                .send_message(imsg::Message::min(), None, &too_large)
+                .await?;
+            sender
+                .send_message(imsg::Message::min(), None, &"small")
+                .await
+        });
+
+        if policy == imsg::LimitPolicy::Error {
+            let err = receiver.recv_message::<Vec<u8>>().await.expect_err("limit");
+            assert!(matches!(
+                protocol_error(&err),
+                imsg::ProtocolError::LimitExceeded(imsg::Limit::Length)
+            ));
+        }
+
+        // The remaining fragments of the message are skipped.
+        let (_, _, data) = receiver.recv_message::<String>().await?.expect("message");
+        assert_eq!(data, "small");
+        task.await.expect("task")?;
+    }
+
+    // Limit the message rate and disconnect the peer.
+    let (sender, receiver) = imsg::Handler::pair()?;
+    let receiver = receiver.with_options(imsg::Options {
+        max_rate: Some(2),
+        limit_policy: imsg::LimitPolicy::Disconnect,
+        ..Default::default()
+    });
+    for i in 0..3u32 {
+        sender.send_message(imsg::Message::min(), None, &i).await?;
+    }
+    for i in 0..2u32 {
+        let (_, _, data) = receiver.recv_message::<u32>().await?.expect("message");
+        assert_eq!(data, i);
+    }
+    let err = receiver.recv_message::<u32>().await.expect_err("rate");
+    assert!(matches!(
+        protocol_error(&err),
+        imsg::ProtocolError::LimitExceeded(imsg::Limit::Rate)
+    ));
+    assert!(sender.recv_message::<()>().await?.is_none());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_imsg_capture() -> Result<(), io::Error> {
+    use imsg::capture::{CaptureReader, Direction, Record};
+
+    let path = std::env::temp_dir().join(format!("imsg-capture-{}.cap", std::process::id()));
+    let options = imsg::Options {
+        capture: Some(Arc::new(imsg::Capture::create(&path)?)),
+        ..Default::default()
+    };
+    let (sender, receiver) = imsg::Handler::pair()?;
+    let sender = sender.with_options(options.clone());
+    let receiver = receiver.with_options(options);
+
+    let fd = Fd::from(TcpListener::bind("127.0.0.1:0")?.into_raw_fd());
+    let large = vec![0x55u8; 150_000];
+    let task = tokio::spawn(async move {
+        sender
+            .send_message(imsg::Message::min(), Some(&fd), &"hello")
+            .await?;
+        sender
+            .send_message(imsg::Message::min(), None, &large)
+            .await
+    });
+    let (_, fd, data) = receiver.recv_message::<String>().await?.expect("message");
+    assert!(fd.is_some());
+    assert_eq!(data, "hello");
+    let (_, _, data) = receiver.recv_message::<Vec<u8>>().await?.expect("message");
+    assert_eq!(data.len(), 150_000);
+    task.await.expect("task")?;
+
+    let records = CaptureReader::open(&path)?.collect::<Result<Vec<_>, _>>()?;
+    std::fs::remove_file(&path)?;
+
+    // One frame with an fd and three fragments in each direction.
+    let sent = records
+        .iter()
+        .filter(|record| record.direction == Direction::Sent)
+        .cloned()
+        .collect::<Vec<_>>();
+    assert_eq!(records.len(), 2 * sent.len());
+    assert_eq!(sent.len(), 4);
+    assert_eq!(sent[0].fds, 1);
+    assert!(sent[1..].iter().all(|record| record.fds == 0));
+    assert_eq!(
+        sent.iter().map(|record| record.data.len()).sum::<usize>(),
+        records
+            .iter()
+            .filter(|record| record.direction == Direction::Received)
+            .map(|record| record.data.len())
+            .sum::<usize>()
+    );
+
+    // Replay the sent frames.
+    let (sender, receiver) = imsg::Handler::pair()?;
+    let task = tokio::spawn(async move {
+        for record in sent {
+            record.replay(&sender).await?;
+        }
+        Ok::<_, io::Error>(())
+    });
+    let first = Record::recv(&receiver).await?.expect("record");
+    assert_eq!((first.fds, first.data.len()), (1, records[0].data.len()));
+    let second = Record::recv(&receiver).await?.expect("record");
+    let data = bincode::deserialize::<Vec<u8>>(&second.data).expect("payload");
+    assert_eq!(data, vec![0x55u8; 150_000]);
+    task.await.expect("task")?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_imsg_capture_replay() -> Result<(), io::Error> {
+    use imsg::capture::{Direction, Record, Replay};
+
+    let record = |id, flags, data: &[u8]| Record {
+        direction: Direction::Sent,
+        time: std::time::SystemTime::now(),
+        message: imsg::Message {
+            length: (imsg::Message::HEADER_LENGTH + data.len()) as u16,
+            flags,
+            ..imsg::Message::new(id)
+        },
+        fds: 0,
+        data: data.to_vec(),
+    };
+    let records = [
+        record(imsg::Message::CONNECT, 0, b"connect"),
+        record(imsg::Message::RESERVED, 0x0010, b"malformed"),
+        record(imsg::Message::RESERVED, 0, b"hello"),
+    ];
+
+    let mut replay = Replay::new(Default::default())?;
+
+    // The reserved ID is accepted.
+    let received = replay.replay(&records[0]).await?.expect("record");
+    assert_eq!(received.message.id, imsg::Message::CONNECT);
+    assert_eq!(received.data, b"connect");
+
+    // The malformed frame is reported.
+    let err = replay.replay(&records[1]).await.expect_err("malformed");
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+    // The replay continues after the framing error.
+    let received = replay.replay(&records[2]).await?.expect("record");
+    assert_eq!(received.message.id, imsg::Message::RESERVED);
+    assert_eq!(received.data, b"hello");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_imsg_capture_keepalive() -> Result<(), io::Error> {
+    use imsg::capture::{CaptureReader, Record};
+
+    let path = std::env::temp_dir().join(format!("imsg-keepalive-{}.cap", std::process::id()));
+    let options = imsg::Options {
+        capture: Some(Arc::new(imsg::Capture::create(&path)?)),
+        ..Default::default()
+    };
+    let (sender, receiver) = imsg::Handler::pair()?;
+    let sender = sender.with_options(options.clone());
+    let receiver = receiver.with_options(options);
+    receiver.accept_reserved(imsg::Message::PING, true);
+
+    // The PING is answered with a PONG while the message is received.
+    sender.ping()?;
+    sender
+        .send_message(imsg::Message::min(), None, &"hello")
+        .await?;
+    let (_, _, data) = receiver.recv_message::<String>().await?.expect("message");
+    assert_eq!(data, "hello");
+    let recv = tokio::time::timeout(Duration::from_millis(20), sender.recv_message::<String>());
+    assert!(recv.await.is_err());
+
+    let records = CaptureReader::open(&path)?.collect::<Result<Vec<_>, _>>()?;
+    std::fs::remove_file(&path)?;
+
+    // Only the message is captured, in both directions.
+    assert_eq!(records.len(), 2);
+    assert!(records
+        .iter()
+        .all(|record| record.message.id == imsg::Message::min().id));
+
+    // Every replayed record is received.
+    let (sender, receiver) = imsg::Handler::pair()?;
+    for record in records {
+        record.replay(&sender).await?;
+        let recv = tokio::time::timeout(Duration::from_secs(1), Record::recv(&receiver));
+        assert!(recv.await.expect("record")?.is_some());
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_imsg_metrics() -> Result<(), io::Error> {
+    let (sender, receiver) = imsg::Handler::pair()?;
+    let fd = Fd::from(TcpListener::bind("127.0.0.1:0")?.into_raw_fd());
+
+    sender
+        .send_message(imsg::Message::min(), Some(&fd), &"hello")
+        .await?;
+    let task = tokio::spawn(async move {
+        for _ in 0..2 {
+            sender
+                .send_message(imsg::Message::min(), None, &vec![0u8; 100_000])
+                .await?;
+        }
+        sender
+            .send_message(imsg::Message::min(), None, &1u8)
+            .await?;
+        Ok::<_, io::Error>(sender)
+    });
+
+    let (_, fd, _) = receiver.recv_message::<String>().await?.expect("message");
+    assert!(fd.is_some());
+    receiver.recv_message::<Vec<u8>>().await?.expect("message");
+    receiver.recv_message::<Vec<u8>>().await?.expect("message");
+    // A `u8` payload cannot be decoded as a `String`.
+    assert!(receiver.recv_message::<String>().await.is_err());
+    let sender = task.await.expect("task")?;
+
+    let sent = sender.metrics();
+    let received = receiver.metrics();
+    assert_eq!(sent.messages_sent, 4);
+    assert_eq!(received.messages_received, 4);
+    assert_eq!(sent.bytes_sent, received.bytes_received);
+    assert_eq!((sent.fds_sent, received.fds_received), (1, 1));
+    assert_eq!(received.decode_errors, 1);
+    assert_eq!(sent.queued, 0);
+    assert!(sent.max_queued > 100_000);
+    assert_eq!(sent.send_latency.count, 4);
+    assert!(sent.send_latency.quantile(0.5).is_some());
+
+    // Aggregate the metrics of both ends.
+    let total = [sent, received].iter().copied().sum::<imsg::Metrics>();
+    assert_eq!(total.messages_sent + total.messages_received, 8);
+    assert_eq!(total.send_latency.count, 4);
+
+    Ok(())
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_imsg_ping() -> Result<(), io::Error> {
+    let (sender, receiver) = imsg::Handler::pair()?;
+    receiver.accept_reserved(imsg::Message::PING, true);
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(sender.idle() >= Duration::from_millis(100));
+
+    // The request is answered and not returned to the receiver.
+    sender.ping()?;
+    sender
+        .send_message(imsg::Message::min(), None, &"hello")
+        .await?;
+    let (_, _, data) = receiver.recv_message::<String>().await?.expect("message");
+    assert_eq!(data, "hello");
+
+    // Reading the answer resets the idle time of the sender.
+    let recv = tokio::time::timeout(Duration::from_millis(20), sender.recv_message::<String>());
+    assert!(recv.await.is_err());
+    assert!(sender.idle() < Duration::from_millis(100));
+
+    // Keepalives are not counted as messages on either end.
+    let (sent, received) = (sender.metrics(), receiver.metrics());
+    assert_eq!((sent.messages_sent, sent.messages_received), (1, 0));
+    assert_eq!((received.messages_sent, received.messages_received), (0, 1));
+    assert_eq!(sent.bytes_sent, received.bytes_received);
+    assert_eq!(sent.bytes_received, received.bytes_sent);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_imsg_ping_limits() -> Result<(), io::Error> {
+    let limit = |err: io::Error| match err.get_ref().and_then(|err| err.downcast_ref()) {
+        Some(imsg::ProtocolError::LimitExceeded(limit)) => Some(*limit),
+        _ => None,
+    };
+
+    // A peer that does not accept keepalives doesn't answer them.
+    let (sender, receiver) = imsg::Handler::pair()?;
+    sender.ping()?;
+    sender.flush().await?;
+    let err = receiver.recv_message::<()>().await.expect_err("reserved");
+    assert!(matches!(
+        err.get_ref().and_then(|err| err.downcast_ref()),
+        Some(imsg::ProtocolError::ReservedId(imsg::Message::PING))
+    ));
+    assert_eq!(receiver.metrics().bytes_sent, 0);
+
+    // Keepalives count against the message rate.
+    let (sender, receiver) = imsg::Handler::pair()?;
+    let receiver = receiver.with_options(imsg::Options {
+        max_rate: Some(1),
+        ..Default::default()
+    });
+    receiver.accept_reserved(imsg::Message::PING, true);
+    for _ in 0..2 {
+        sender.ping()?;
+    }
+    sender.flush().await?;
+    let err = receiver.recv_message::<()>().await.expect_err("rate");
+    assert_eq!(limit(err), Some(imsg::Limit::Rate));
+
+    // The answers that the sender does not read are limited.
+    let (sender, receiver) = imsg::Handler::pair()?;
+    let receiver = receiver.with_options(imsg::Options {
+        max_buffered: 0,
+        ..Default::default()
+    });
+    receiver.accept_reserved(imsg::Message::PING, true);
+    sender.ping()?;
+    sender.flush().await?;
+    let err = receiver.recv_message::<()>().await.expect_err("buffered");
+    assert_eq!(limit(err), Some(imsg::Limit::Buffered));
+    assert_eq!(receiver.metrics().dropped, 1);
+
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "macos", target_os = "ios")))]
+#[tokio::test]
+async fn test_imsg_seqpacket() -> Result<(), io::Error> {
+    use privsep::net::{SocketAncillary, Transport, UnixStreamExt};
+
+    let (sender, receiver) = imsg::Handler::pair_with(Transport::SeqPacket)?;
+    assert_eq!(sender.transport(), Transport::SeqPacket);
+    assert_eq!(receiver.transport(), Transport::SeqPacket);
+
+    let fd = Fd::from(TcpListener::bind("127.0.0.1:0")?.into_raw_fd());
+    let task = tokio::spawn(async move {
+        sender
+            .send_message(imsg::Message::min(), Some(&fd), &"hello")
+            .await?;
+        // Each fragment is a separate record.
+        sender
+            .send_message(imsg::Message::min(), None, &vec![0x55u8; 150_000])
+            .await?;
+        Ok::<_, io::Error>(sender)
+    });
+
+    let (_, fd, data) = receiver.recv_message::<String>().await?.expect("message");
+    assert!(fd.is_some());
+    assert_eq!(data, "hello");
+    let (_, fd, data) = receiver.recv_message::<Vec<u8>>().await?.expect("message");
+    assert!(fd.is_none());
+    assert_eq!(data, vec![0x55u8; 150_000]);
+    let sender = task.await.expect("task")?;
+
+    // The remote end detects the transport of the channel.
+    let (left, right) = imsg::Handler::socketpair_with(Transport::SeqPacket)?;
+    let remote = imsg::Handler::from_raw_fd(right)?;
+    assert_eq!(remote.transport(), Transport::SeqPacket);
+
+    // A record that does not contain exactly one frame is rejected.
+    let mut record = imsg::Message::min();
+    record.length = imsg::Message::HEADER_LENGTH as u16;
+    let mut data = record.as_bytes().to_vec();
+    data.extend_from_slice(b"trailing");
+    nix::sys::socket::send(left.as_raw_fd(), &data, nix::sys::socket::MsgFlags::empty())?;
+    let err = remote
+        .recv_message::<()>()
+        .await
+        .expect_err("invalid record");
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+    // The sockets are not inherited by executed programs.
+    let (a, b) = Transport::SeqPacket.pair()?;
+    for socket in [&a, &b] {
+        let flags = nix::fcntl::fcntl(socket.as_raw_fd(), nix::fcntl::FcntlArg::F_GETFD)?;
+        assert_ne!(flags & nix::libc::FD_CLOEXEC, 0);
+    }
+
+    // An empty record would be read as the end of file.
+    let mut buffer = [0u8; 64];
+    let mut ancillary = SocketAncillary::new(&mut buffer);
+    let err = a
+        .send_vectored_with_ancillary(&[io::IoSlice::new(&[])], &mut ancillary)
+        .await
+        .expect_err("empty record");
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+    drop(sender);
+    assert!(receiver.recv_message::<()>().await?.is_none());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_imsg_typed_fds() -> Result<(), Error> {
+    let (sender, receiver) = imsg::Handler::pair()?;
+
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let listener = Fd::from(listener.into_raw_fd());
+    let socket = Fd::from(UdpSocket::bind("127.0.0.1:0")?.into_raw_fd());
+    let file = Fd::from(File::open("/dev/null")?.into_raw_fd());
+
+    sender
+        .send_message_with_typed_fds(imsg::Message::min(), &[&listener, &socket, &file], &"fds")
+        .await?;
+    let (_, fds, data) = receiver
+        .recv_message_with_typed_fds::<String>()
+        .await?
+        .expect("message");
+    assert_eq!(data, "fds");
+    let kinds = fds.iter().map(|fd| fd.kind()).collect::<Vec<_>>();
+    assert_eq!(
+        kinds,
+        [FdKind::TcpListener, FdKind::UdpSocket, FdKind::File]
+    );
+
+    // Convert into safe objects of the matching kind only.
+    let mut fds = fds.into_iter();
+    let listener = fds.next().expect("listener");
+    let socket = fds.next().expect("socket");
+    let file = fds.next().expect("file");
+    match socket.into_typed::<tokio::net::TcpStream>() {
+        Err(Error::IoError(err)) => assert!(matches!(
+            protocol_error(&err),
+            imsg::ProtocolError::InvalidFdKind(FdKind::TcpStream, FdKind::UdpSocket)
+        )),
+        result => panic!("unexpected result: {:?}", result),
+    }
+    let listener = listener.into_typed::<tokio::net::TcpListener>()?;
+    assert_eq!(listener.local_addr()?, addr);
+    let _file = file.into_typed::<File>()?;
+
+    // The receiver verifies the kinds that were sent.
+    let fd = Fd::from(TcpListener::bind("127.0.0.1:0")?.into_raw_fd());
+    sender
+        .send_message_with_fds(
+            imsg::Message::min(),
+            &[&fd],
+            &(vec![FdKind::UdpSocket], "fds"),
+        )
+        .await?;
+    let err = receiver
+        .recv_message_with_typed_fds::<String>()
+        .await
+        .expect_err("invalid kind");
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert_eq!(receiver.metrics().protocol_errors, 1);
+
+    // Untagged fds are verified when they are converted.
+    let fd = fd.into_typed::<std::net::TcpListener>()?;
+    assert!(fd.local_addr().is_ok());
+
+    // The split halves pass typed fds as well.
+    let (_, writer) = sender.split();
+    let (mut reader, _) = receiver.split();
+    let file = Fd::from(File::open("/dev/null")?.into_raw_fd());
+    writer
+        .send_message_with_typed_fds(imsg::Message::min(), &[&file], &"split")
+        .await?;
+    let (_, fds, data) = reader
+        .recv_message_with_typed_fds::<String>()
+        .await?
+        .expect("message");
+    assert_eq!(data, "split");
+    assert_eq!(fds[0].kind(), FdKind::File);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_imsg_typed_unix_fds() -> Result<(), Error> {
+    use privsep::net::Transport;
+    use std::os::unix::net::{UnixDatagram, UnixStream};
+
+    let (sender, receiver) = imsg::Handler::pair()?;
+    let (stream, _) = imsg::Handler::socketpair_with(Transport::Stream)?;
+    let (datagram, _) = UnixDatagram::pair()?;
+    let datagram = Fd::from(datagram.into_raw_fd());
+    let mut fds = vec![&stream, &datagram];
+    let mut expected = vec![FdKind::UnixStream, FdKind::UnixDatagram];
+
+    // The socket type is not supported on every platform.
+    let seqpacket = imsg::Handler::socketpair_with(Transport::SeqPacket).ok();
+    if let Some((ref seqpacket, _)) = seqpacket {
+        fds.push(seqpacket);
+        expected.push(FdKind::UnixSeqPacket);
+    }
+
+    sender
+        .send_message_with_typed_fds(imsg::Message::min(), &fds, &())
+        .await?;
+    let (_, fds, ()) = receiver
+        .recv_message_with_typed_fds()
+        .await?
+        .expect("message");
+    let kinds = fds.iter().map(|fd| fd.kind()).collect::<Vec<_>>();
+    assert_eq!(kinds, expected);
+
+    // Each kind only converts into its own socket type.
+    let mut fds = fds.into_iter();
+    let stream = fds.next().expect("stream");
+    let datagram = fds.next().expect("datagram");
+    match datagram.into_typed::<UnixStream>() {
+        Err(Error::IoError(err)) => assert!(matches!(
+            protocol_error(&err),
+            imsg::ProtocolError::InvalidFdKind(FdKind::UnixStream, FdKind::UnixDatagram)
+        )),
+        result => panic!("unexpected result: {:?}", result),
+    }
+    let _stream = stream.into_typed::<UnixStream>()?;
+    if let Some(seqpacket) = fds.next() {
+        assert!(seqpacket.into_typed::<UnixDatagram>().is_err());
+    }
+
+    Ok(())
+}
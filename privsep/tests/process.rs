@@ -0,0 +1,162 @@
+use privsep::{
+    imsg,
+    net::Fd,
+    process::{Keepalive, WatchdogAction},
+    Error,
+};
+use std::{
+    io,
+    net::TcpListener,
+    os::unix::io::{AsRawFd, FromRawFd, IntoRawFd},
+    time::Duration,
+};
+
+mod common;
+
+const HELLO: u32 = 10;
+
+#[tokio::test]
+async fn test_broadcast() -> Result<(), Error> {
+    let (first, remote_first) = imsg::Handler::pair()?;
+    let (second, remote_second) = imsg::Handler::pair()?;
+    let (closed, remote_closed) = imsg::Handler::pair()?;
+    drop(remote_closed);
+
+    let parent = common::parent([
+        ("parent", None),
+        ("first", Some(first)),
+        ("second", Some(second)),
+        ("closed", Some(closed)),
+    ]);
+
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let fd = Fd::from(listener.into_raw_fd());
+
+    // The closed channel fails but doesn't stop the other sends.
+    let err = parent
+        .broadcast(imsg::Message::new(HELLO), Some(&fd), &"hello")
+        .await
+        .expect_err("closed peer");
+    match err {
+        Error::SendFailed(errors) => {
+            assert_eq!(errors.len(), 1);
+            assert_eq!(errors[0].0, 3);
+        }
+        err => panic!("unexpected error: {}", err),
+    }
+
+    // Each child received its own duplicate of the fd.
+    let mut fds = vec![];
+    for remote in [&remote_first, &remote_second] {
+        let (message, received, data) = remote.recv_message::<String>().await?.expect("message");
+        assert_eq!((message.id, data.as_str()), (HELLO, "hello"));
+        let received = received.expect("fd");
+        assert_ne!(received.as_raw_fd(), fd.as_raw_fd());
+        let listener = unsafe { TcpListener::from_raw_fd(received.into_raw_fd()) };
+        assert_eq!(listener.local_addr()?, addr);
+        fds.push(listener);
+    }
+
+    // Send to a subset; unconnected peers are reported.
+    let err = parent
+        .multicast(&[0, 2], imsg::Message::new(HELLO), None, &"second")
+        .await
+        .expect_err("unconnected peer");
+    assert!(matches!(
+        err,
+        Error::SendFailed(ref errors)
+            if errors.len() == 1
+                && errors[0].0 == 0
+                && errors[0].1.kind() == io::ErrorKind::NotConnected
+    ));
+    let (_, _, data) = remote_second
+        .recv_message::<String>()
+        .await?
+        .expect("message");
+    assert_eq!(data, "second");
+
+    parent
+        .multicast(&[1], imsg::Message::new(HELLO), None, &"first")
+        .await?;
+    let (_, _, data) = remote_first
+        .recv_message::<String>()
+        .await?
+        .expect("message");
+    assert_eq!(data, "first");
+
+    Ok(())
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_watchdog() -> Result<(), Error> {
+    let (alive, remote_alive) = imsg::Handler::pair()?;
+    let (stuck, _remote_stuck) = imsg::Handler::pair()?;
+
+    let parent = common::parent([("parent", None), ("a", Some(alive)), ("b", Some(stuck))]);
+    remote_alive.accept_reserved(imsg::Message::PING, true);
+
+    // Only the first child reads and answers the keepalive requests.
+    let reader = tokio::spawn(async move {
+        while remote_alive.recv_message::<()>().await?.is_some() {}
+        Ok::<_, io::Error>(())
+    });
+
+    // The paused clock also advances while an answer is in flight, so
+    // the responsive child may miss a few intervals.
+    let keepalive = Keepalive {
+        interval: Duration::from_millis(20),
+        misses: 10,
+        action: WatchdogAction::Terminate,
+    };
+    // The parent must read to receive the answers.
+    let err = tokio::select! {
+        res = parent.watchdog(&keepalive) => res.expect_err("stuck child"),
+        res = parent[1].recv_message::<()>() => panic!("unexpected message: {:?}", res),
+    };
+    assert!(matches!(err, Error::Terminated("b")), "{}", err);
+    assert!(parent[1].idle() < Duration::from_millis(200));
+    reader.abort();
+
+    // The interval and the number of misses must not be zero.
+    for keepalive in [
+        Keepalive {
+            interval: Duration::ZERO,
+            ..keepalive
+        },
+        Keepalive {
+            misses: 0,
+            ..keepalive
+        },
+    ] {
+        match parent.watchdog(&keepalive).await {
+            Err(Error::IoError(err)) => assert_eq!(err.kind(), io::ErrorKind::InvalidInput),
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_executable_id() -> Result<(), Error> {
+    let id = privsep::process::executable_id()?;
+    assert!(!id.is_empty());
+    assert!(id.chars().all(|c| c.is_ascii_hexdigit()));
+    assert_eq!(id, privsep::process::executable_id()?);
+
+    // Compare with the note that the linker added, if any.
+    let output = std::process::Command::new("readelf")
+        .arg("-n")
+        .arg(std::env::current_exe()?)
+        .output();
+    if let Some(build_id) = output.ok().and_then(|output| {
+        String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .find_map(|line| line.trim().strip_prefix("Build ID: ").map(str::to_owned))
+    }) {
+        assert_eq!(id, build_id);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,184 @@
+use futures::future::join_all;
+use privsep::{
+    imsg::{self, rpc::Rpc},
+    Error,
+};
+use std::{io, time::Duration};
+
+const ADD: u32 = 10;
+const FAIL: u32 = 11;
+const IGNORE: u32 = 12;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_rpc() -> Result<(), Error> {
+    let (client, server) = imsg::Handler::pair()?;
+
+    tokio::spawn(async move { serve(server).await.map_err(|err| err.to_string()) });
+
+    let rpc = Rpc::new(&client);
+    let timeout = Duration::from_secs(5);
+
+    let requests = (1..=4u32).map(|a| (a, 10u32)).collect::<Vec<_>>();
+    let calls = requests
+        .iter()
+        .map(|request| rpc.call::<_, u32>(ADD.into(), request, timeout));
+    let results = join_all(calls).await;
+    for (a, result) in (1..=4u32).zip(results) {
+        assert_eq!(result?, a + 10);
+    }
+
+    match rpc.call::<_, ()>(FAIL.into(), &(), timeout).await {
+        Err(Error::RemoteError(err)) => assert_eq!(err, "failed"),
+        result => panic!("unexpected result: {:?}", result),
+    }
+
+    match rpc
+        .call::<_, ()>(IGNORE.into(), &(), Duration::from_millis(100))
+        .await
+    {
+        Err(Error::IoError(err)) => assert_eq!(err.kind(), io::ErrorKind::TimedOut),
+        result => panic!("unexpected result: {:?}", result),
+    }
+
+    // The channel is still usable after a timeout.
+    assert_eq!(
+        rpc.call::<_, u32>(ADD.into(), &(4u32, 1u32), timeout)
+            .await?,
+        5
+    );
+
+    Ok(())
+}
+
+async fn serve(server: imsg::Handler) -> Result<(), Error> {
+    let rpc = Rpc::new(&server);
+    loop {
+        let request = match rpc.recv_request().await? {
+            Some(request) => request,
+            None => break Ok(()),
+        };
+        match request.message.id {
+            ADD => {
+                let (a, b) = request.decode::<(u32, u32)>()?;
+                // Answer the calls out of order.
+                tokio::time::sleep(Duration::from_millis(u64::from(10 * (5 - a)))).await;
+                rpc.respond(&request, Ok(a + b)).await?;
+            }
+            FAIL => {
+                rpc.respond::<()>(&request, Err("failed".to_string()))
+                    .await?
+            }
+            _ => {}
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_rpc_malformed_response() -> Result<(), Error> {
+    let (client, server) = imsg::Handler::pair()?;
+    let (rpc, server) = (Rpc::new(&client), &server);
+    let timeout = Duration::from_secs(5);
+
+    // The server answers with the raw envelopes of the calls.
+    let response = |call_id, data: Result<Vec<u8>, String>| async move {
+        let message = imsg::Message {
+            flags: imsg::Message::FLAG_RESPONSE,
+            ..imsg::Message::new(ADD)
+        };
+        server.send_message(message, None, &(call_id, data)).await
+    };
+    let recv_call_id = || async {
+        let (_, _, (call_id, _)) = server
+            .recv_message::<(u32, Vec<u8>)>()
+            .await?
+            .expect("request");
+        Ok::<u32, io::Error>(call_id)
+    };
+    let invalid_data = |result: Result<u32, Error>| match result {
+        Err(Error::IoError(err)) => assert_eq!(err.kind(), io::ErrorKind::InvalidData),
+        result => panic!("unexpected result: {:?}", result),
+    };
+
+    // A malformed payload only fails its own call.
+    let server_task = async {
+        let first = recv_call_id().await?;
+        let second = recv_call_id().await?;
+        response(first, Ok(vec![0xff])).await?;
+        response(second, Ok(5u32.to_le_bytes().to_vec())).await
+    };
+    let (first, second, result) = tokio::join!(
+        rpc.call::<_, u32>(ADD.into(), &(1u32, 2u32), timeout),
+        rpc.call::<_, u32>(ADD.into(), &(2u32, 3u32), timeout),
+        server_task,
+    );
+    result?;
+    invalid_data(first);
+    assert_eq!(second?, 5);
+
+    // A malformed envelope has no call ID and fails all pending calls.
+    let server_task = async {
+        recv_call_id().await?;
+        recv_call_id().await?;
+        let message = imsg::Message {
+            flags: imsg::Message::FLAG_RESPONSE,
+            ..imsg::Message::new(ADD)
+        };
+        server.send_message(message, None, &()).await
+    };
+    let (first, second, result) = tokio::join!(
+        rpc.call::<_, u32>(ADD.into(), &(1u32, 2u32), timeout),
+        rpc.call::<_, u32>(ADD.into(), &(2u32, 3u32), timeout),
+        server_task,
+    );
+    result?;
+    invalid_data(first);
+    invalid_data(second);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_rpc_receive_error() -> Result<(), Error> {
+    let (client, server) = imsg::Handler::pair()?;
+    let client = client.with_options(imsg::Options {
+        max_length: 64,
+        ..Default::default()
+    });
+    let (rpc, server) = (Rpc::new(&client), &server);
+    let timeout = Duration::from_secs(5);
+
+    // The response exceeds the limit and cannot be assigned to a call.
+    let server_task = async {
+        let (_, _, (call_id, _)) = server
+            .recv_message::<(u32, Vec<u8>)>()
+            .await?
+            .expect("request");
+        server.recv_message::<(u32, Vec<u8>)>().await?;
+        let message = imsg::Message {
+            flags: imsg::Message::FLAG_RESPONSE,
+            ..imsg::Message::new(ADD)
+        };
+        let data: Result<Vec<u8>, String> = Ok(vec![0; 1024]);
+        server.send_message(message, None, &(call_id, data)).await
+    };
+    let (first, second, result) = tokio::join!(
+        rpc.call::<_, u32>(ADD.into(), &(1u32, 2u32), timeout),
+        rpc.call::<_, u32>(ADD.into(), &(2u32, 3u32), timeout),
+        server_task,
+    );
+    result?;
+
+    // All pending calls and the next request fail with the error.
+    for result in [first, second] {
+        match result {
+            Err(Error::IoError(err)) => assert_eq!(err.kind(), io::ErrorKind::InvalidData),
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+    match rpc.recv_request().await {
+        Err(Error::IoError(err)) => assert_eq!(err.kind(), io::ErrorKind::InvalidData),
+        result => panic!("unexpected result: {:?}", result),
+    }
+
+    Ok(())
+}
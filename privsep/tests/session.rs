@@ -0,0 +1,266 @@
+use futures::future::join_all;
+use privsep::imsg::{
+    self,
+    session::{Mux, Session},
+};
+use std::io;
+
+const HELLO: u32 = 10;
+
+#[tokio::test]
+async fn test_session() -> Result<(), io::Error> {
+    let (client, server) = imsg::Handler::pair()?;
+
+    let server = async {
+        let mux = Mux::new(&server);
+        let mut sessions = vec![];
+        for _ in 0..2 {
+            sessions.push(mux.accept().await?.expect("session"));
+        }
+
+        // Echo the messages of each session until it is closed.
+        let echo = sessions.iter().map(|session| async move {
+            let mut count = 0;
+            while let Some((message, _, data)) = session.recv_message::<String>().await? {
+                session
+                    .send_message(message, None, &format!("{} {}", session.id(), data))
+                    .await?;
+                count += 1;
+            }
+            session.closed().await?;
+            Ok::<_, io::Error>(count)
+        });
+        let counts = join_all(echo)
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // The client closed the channel.
+        assert!(mux.accept().await?.is_none());
+        Ok::<_, io::Error>(counts)
+    };
+
+    let client = async {
+        let mux = Mux::new(&client);
+        let first = mux.open().await?;
+        let second = mux.open().await?;
+        assert_eq!((first.id(), second.id()), (1, 2));
+
+        second.send_message(HELLO.into(), None, &"b").await?;
+        first.send_message(HELLO.into(), None, &"a").await?;
+        first.send_message(HELLO.into(), None, &"c").await?;
+
+        // The reply of the second session is queued until it is received.
+        for data in ["a", "c"] {
+            let (message, _, reply) = first.recv_message::<String>().await?.expect("reply");
+            assert_eq!(message.peer_id, 1 | Session::REMOTE);
+            assert_eq!(reply, format!("{} {}", 1 | Session::REMOTE, data));
+        }
+        let (_, _, reply) = second.recv_message::<String>().await?.expect("reply");
+        assert_eq!(reply, format!("{} b", 2 | Session::REMOTE));
+
+        first.close().await?;
+        drop(second);
+        drop(mux);
+        drop(client);
+        Ok::<_, io::Error>(())
+    };
+
+    let (counts, result) = tokio::join!(server, client);
+    result?;
+    assert_eq!(counts?, [2, 1]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_session_limits() -> Result<(), io::Error> {
+    let (client, server) = imsg::Handler::pair()?;
+    let server = server.with_options(imsg::Options {
+        max_buffered: 10_000,
+        limit_policy: imsg::LimitPolicy::Drop,
+        ..Default::default()
+    });
+    let large = vec![0x55u8; 4000];
+
+    let server = async {
+        let mux = Mux::new(&server);
+        let data = mux.accept().await?.expect("session");
+        let control = mux.accept().await?.expect("session");
+
+        // The messages of the first session are queued while the
+        // second one is received; the third one exceeds the limit.
+        for _ in 0..3 {
+            let (message, _, ()) = control.recv_message().await?.expect("ping");
+            control.send_message(message, None, &()).await?;
+        }
+        let mut count = 0;
+        while data.recv_message::<Vec<u8>>().await?.is_some() {
+            count += 1;
+        }
+        assert_eq!(server.metrics().dropped, 1);
+
+        // Sessions that exceed the backlog are closed immediately.
+        let first = mux.accept().await?.expect("session");
+        first.closed().await?;
+        Ok::<_, io::Error>(count)
+    };
+
+    let client = async {
+        let mux = Mux::new(&client);
+        let data = mux.open().await?;
+        let control = mux.open().await?;
+        for _ in 0..3 {
+            data.send_message(HELLO.into(), None, &large).await?;
+            control.send_message(HELLO.into(), None, &()).await?;
+            control.recv_message::<()>().await?.expect("pong");
+        }
+        data.close().await?;
+
+        let mut sessions = vec![];
+        for _ in 0..Mux::BACKLOG + 2 {
+            sessions.push(mux.open().await?);
+        }
+        sessions.pop().expect("session").closed().await?;
+        drop(sessions);
+        Ok::<_, io::Error>(())
+    };
+
+    let (count, result) = tokio::join!(server, client);
+    result?;
+    assert_eq!(count?, 2);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_session_close_queued() -> Result<(), io::Error> {
+    let (client, server) = imsg::Handler::pair()?;
+    let client = client.with_options(imsg::Options {
+        high_water_mark: 64 * 1024,
+        ..Default::default()
+    });
+    let client_mux = Mux::new(&client);
+    let server_mux = Mux::new(&server);
+    let large = vec![0x55u8; 16 * 1024];
+
+    // Receive the messages of the next session until it is closed.
+    let recv = || async {
+        let session = server_mux.accept().await?.expect("session");
+        let mut count = 0;
+        while session.recv_message::<Vec<u8>>().await?.is_some() {
+            count += 1;
+        }
+        Ok::<_, io::Error>(count)
+    };
+
+    for drop_session in [true, false] {
+        // Fill the socket until the close has to be queued.
+        let session = client_mux.open().await?;
+        let mut sent = 0;
+        while client.queued() == 0 {
+            session.send_message(HELLO.into(), None, &large).await?;
+            sent += 1;
+        }
+
+        let close = async {
+            if drop_session {
+                drop(session);
+                client_mux.flush().await
+            } else {
+                session.close().await
+            }
+        };
+        let (received, result) = tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            tokio::join!(recv(), close)
+        })
+        .await
+        .expect("close");
+        result?;
+        assert_eq!(received?, sent);
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_session_errors() -> Result<(), io::Error> {
+    use std::{io::Write, os::unix::net::UnixStream};
+    use zerocopy::AsBytes;
+
+    let (left, mut right) = UnixStream::pair()?;
+    left.set_nonblocking(true)?;
+    let client = imsg::Handler::from_raw_fd(left)?;
+    let mux = Mux::new(&client);
+    let first = mux.open().await?;
+    let second = mux.open().await?;
+
+    let mut send = |peer_id: u32, data: &[u8]| {
+        let message = imsg::Message {
+            length: (imsg::Message::HEADER_LENGTH + data.len()) as u16,
+            peer_id,
+            ..imsg::Message::new(HELLO)
+        };
+        right.write_all(&[message.as_bytes(), data].concat())
+    };
+    let kind =
+        |result: io::Result<Option<(imsg::Message, _, ())>>| result.expect_err("error").kind();
+
+    // A message without session is reported to all sessions, after
+    // the messages that were queued before it.
+    send(1 | Session::REMOTE, &[])?;
+    send(0, &[])?;
+    assert_eq!(
+        kind(second.recv_message().await),
+        io::ErrorKind::InvalidData
+    );
+    assert!(first.recv_message::<()>().await?.is_some());
+    assert_eq!(kind(first.recv_message().await), io::ErrorKind::InvalidData);
+
+    // The sessions are still usable.
+    send(2 | Session::REMOTE, &[])?;
+    assert!(second.recv_message::<()>().await?.is_some());
+
+    // After an invalid frame, the channel cannot receive anymore.
+    right.write_all(
+        imsg::Message {
+            length: 4,
+            ..imsg::Message::min()
+        }
+        .as_bytes(),
+    )?;
+    assert_eq!(kind(first.recv_message().await), io::ErrorKind::InvalidData);
+    assert_eq!(
+        second.closed().await.expect_err("error").kind(),
+        io::ErrorKind::InvalidData
+    );
+    for session in [&first, &second] {
+        assert_eq!(
+            kind(session.recv_message().await),
+            io::ErrorKind::InvalidData
+        );
+    }
+    assert!(mux.accept().await.is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_session_drop_mux() -> Result<(), io::Error> {
+    let (client, server) = imsg::Handler::pair()?;
+
+    // The handler rejects session messages after the mux was dropped.
+    drop(Mux::new(&server));
+    let mux = Mux::new(&client);
+    let _session = mux.open().await?;
+
+    let err = server.recv_message::<()>().await.expect_err("reserved");
+    match err.get_ref().and_then(|err| err.downcast_ref()) {
+        Some(imsg::ProtocolError::ReservedId(id)) => {
+            assert_eq!(*id, imsg::Message::SESSION_OPEN)
+        }
+        _ => panic!("unexpected error: {:?}", err),
+    }
+
+    Ok(())
+}